@@ -1,5 +1,5 @@
 .code
-    ; code size is 0x12 bytes
+    ; code size is 0x19 bytes
 
     push 0x3a
     push 0x30
@@ -0,0 +1,166 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+use stopwatch::Stopwatch;
+use vm::VM;
+use vm::asm;
+use vm::backend::Backend;
+
+/// A program which never ends, so it can be run for as many instructions
+/// as needed.
+pub struct Workload {
+    pub name: &'static str,
+    pub source: &'static str,
+}
+
+pub const WORKLOADS: &[Workload] = &[Workload {
+                                         name: "arithmetic",
+                                         source: ARITHMETIC,
+                                     },
+                                     Workload {
+                                         name: "recursion",
+                                         source: RECURSION,
+                                     },
+                                     Workload {
+                                         name: "string_scan",
+                                         source: STRING_SCAN,
+                                     },
+                                     Workload {
+                                         name: "events",
+                                         source: EVENTS,
+                                     }];
+
+const ARITHMETIC: &str = "
+    push 0x00
+loop:
+    inc
+    push 0x03
+    mul
+    push 0x07
+    xor
+    jmp loop";
+
+const RECURSION: &str = "
+    push 0x10                ; depth
+loop:
+    call f
+    jmp loop
+
+f:
+    push 0x00
+    je bottom
+    pop
+    dec
+    call f
+    inc
+    ret
+bottom:
+    pop
+    ret";
+
+const STRING_SCAN: &str = "
+.code
+    push 0x0d                ; len(message)
+    push 0x00
+loop:
+    jge restart
+    load_offs [message]
+    emit output
+    pop
+    inc
+    jmp loop
+restart:
+    pop
+    push 0x00
+    jmp loop
+
+.data
+    message ascii \"Hello World!\\n\"";
+
+const EVENTS: &str = "
+    subscribe 0x10 ping
+    subscribe 0x11 pong
+loop:
+    push 0x01
+    emit 0x10
+    pop
+    jmp loop
+
+ping:
+    emit 0x11
+    reti
+pong:
+    reti";
+
+/// Counts the allocations of each thread. Allocations are measured only
+/// where it's the `#[global_allocator]`, which is why the benchmark has a
+/// binary of its own.
+pub struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Allocations made by the current thread so far.
+pub fn allocations() -> usize {
+    ALLOCATIONS.with(|allocations| allocations.get())
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|allocations| allocations.set(allocations.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.with(|allocations| allocations.set(allocations.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+pub struct Measurement {
+    pub instructions: u64,
+    pub elapsed: Duration,
+    pub allocations: usize,
+}
+
+impl Measurement {
+    pub fn instructions_per_second(&self) -> f64 {
+        self.instructions as f64 / self.elapsed.as_secs_f64()
+    }
+
+    pub fn allocations_per_instruction(&self) -> f64 {
+        self.allocations as f64 / self.instructions as f64
+    }
+}
+
+/// Runs `instructions` instructions of the workload (or less if it ends
+/// anyway).
+pub fn measure(workload: &Workload, backend: Backend, instructions: u64) -> Measurement {
+    let executable = asm::assemble(workload.source).unwrap();
+    let mut vm = VM::new(io::empty(),
+                         io::sink(),
+                         executable,
+                         Arc::new(AtomicBool::new(false)))
+        .unwrap();
+    vm.set_backend(backend);
+    vm.start();
+
+    let allocations_before = allocations();
+    let stopwatch = Stopwatch::start_new();
+    while vm.steps() < instructions && vm.step() {}
+    let elapsed = stopwatch.elapsed();
+
+    Measurement {
+        instructions: vm.steps(),
+        elapsed,
+        allocations: allocations() - allocations_before,
+    }
+}
@@ -0,0 +1,47 @@
+extern crate lulzvm;
+
+extern crate clap;
+
+use clap::App;
+use lulzvm::bench::{self, CountingAllocator, WORKLOADS};
+use lulzvm::vm::backend::Backend;
+use std::process;
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn main() {
+    let matches = App::new("LulzVM benchmark")
+        .about("Runs the benchmark workloads with every backend")
+        .args_from_usage("--instructions=[N] 'Instructions per workload (1000000 by default)'")
+        .get_matches();
+
+    let instructions = match matches.value_of("instructions") {
+        Some(n) => {
+            match n.parse() {
+                Ok(n) => n,
+                Err(_) => {
+                    println!("Error: invalid --instructions");
+                    process::exit(1);
+                }
+            }
+        }
+        None => 1_000_000,
+    };
+
+    println!("{:<12} {:<12} {:>14} {:>24}",
+             "workload",
+             "backend",
+             "instructions/s",
+             "allocations/instruction");
+    for workload in WORKLOADS {
+        for &backend in &[Backend::Interpreter, Backend::Predecoded, Backend::Fused] {
+            let measurement = bench::measure(workload, backend, instructions);
+            println!("{:<12} {:<12} {:>14.0} {:>24.3}",
+                     workload.name,
+                     format!("{:?}", backend).to_lowercase(),
+                     measurement.instructions_per_second(),
+                     measurement.allocations_per_instruction());
+        }
+    }
+}
@@ -6,42 +6,153 @@ extern crate lulzvm;
 extern crate log;
 extern crate env_logger;
 
-#[macro_use]
 extern crate clap;
 
-use clap::{ArgGroup, ArgMatches, App};
+use clap::{AppSettings, ArgGroup, ArgMatches, App, SubCommand};
 use lulzvm::vm::VM;
+use lulzvm::vm::asm;
+use lulzvm::vm::backend::Backend;
+use lulzvm::vm::devices::fs::{Filesystem, HostDir, Quota};
+use lulzvm::vm::input::AsyncReader;
+use lulzvm::vm::journal::Journal;
+use lulzvm::vm::memory::ProgramTooLarge;
+use lulzvm::vm::status::{ExitStatus, FaultKind};
+use lulzvm::vm::verifier::Diagnostic;
+use std::cmp;
 use std::env;
 use std::fs::File;
-use std::io::{stdin, stdout, Read, Result};
+use std::io::{self, stdin, stdout, Read, Result, Write};
+use std::process;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 
 fn main() {
     let matches = App::new("LulzVM")
         .args_from_usage("[FILE] 'Bytecode executable'
-                            -d, --debug 'Enable debug messages'")
+                            -d, --debug 'Enable debug messages'
+                            --record=[JOURNAL] 'Record nondeterministic inputs'
+                            --replay=[JOURNAL] 'Replay recorded inputs'
+                            --fs-root=[DIR] 'Let the program access files in DIR'
+                            --checked-heap 'Fault on access to unallocated heap memory'
+                            --verify 'Verify the bytecode before running it'
+                            --predecoded 'Decode the code once before running it'
+                            --fused 'Also execute common sequences in one step'")
         .group(ArgGroup::with_name("required")
             .args(&["FILE"])
             .required(true))
+        .group(ArgGroup::with_name("journal")
+            .args(&["record", "replay"]))
+        .subcommand(SubCommand::with_name("verify")
+            .about("Verifies the bytecode without running it")
+            .args_from_usage("<FILE> 'Bytecode executable'"))
+        .subcommand(SubCommand::with_name("asm")
+            .about("Assembles the source into a bytecode executable")
+            .args_from_usage("<SOURCE> 'Assembly source'
+                              <OUTPUT> 'Bytecode executable to write'"))
+        .subcommand(SubCommand::with_name("disasm")
+            .about("Prints the bytecode as assembly source")
+            .args_from_usage("<FILE> 'Bytecode executable'"))
+        .setting(AppSettings::SubcommandsNegateReqs)
         .get_matches();
 
-    match do_checked_main(matches) {
-        Ok(_) => (),
-        Err(e) => println!("Error: {:?}", e),
+    let result = match matches.subcommand() {
+        ("verify", Some(matches)) => do_verify(matches),
+        ("asm", Some(matches)) => do_asm(matches),
+        ("disasm", Some(matches)) => do_disasm(matches),
+        _ => do_checked_main(matches),
+    };
+
+    let code = match result {
+        Ok(status) => exit_code(status),
+        Err(e) => {
+            println!("Error: {:?}", e);
+            ERROR_EXIT_CODE
+        }
+    };
+
+    process::exit(code);
+}
+
+/// Exit codes of the program, larger TERMINATE arguments are clamped to it.
+/// The codes above are left to interrupts and faults, like signals in a
+/// shell.
+const MAX_EXIT_CODE: u8 = 127;
+
+/// lulzvm itself has failed (e.g. to load or verify the program), which a
+/// program can't report as its own exit code.
+const ERROR_EXIT_CODE: i32 = 128;
+
+fn exit_code(status: ExitStatus) -> i32 {
+    match status {
+        ExitStatus::Finished => 0,
+        ExitStatus::Terminated(code) => cmp::min(code, MAX_EXIT_CODE) as i32,
+        ExitStatus::Interrupted => 130,
+        ExitStatus::Fault(FaultKind::Segfault, _) => 139,
+        ExitStatus::Fault(FaultKind::UnknownError, _) => 134,
     }
 }
 
-fn do_checked_main(matches: ArgMatches) -> Result<()> {
+fn load_executable(matches: &ArgMatches) -> Result<Vec<u8>> {
     let executable_filename = matches.value_of("FILE").unwrap();
 
     let mut executable = Vec::new();
-    let mut executable_file = try!(File::open(executable_filename));
-    let _ = try!(executable_file.read_to_end(&mut executable));
+    let mut executable_file = File::open(executable_filename)?;
+    let _ = executable_file.read_to_end(&mut executable)?;
+    Ok(executable)
+}
+
+fn too_large(e: ProgramTooLarge) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+fn report(diagnostics: Vec<Diagnostic>) -> io::Error {
+    for diagnostic in &diagnostics {
+        println!("{}", diagnostic);
+    }
+
+    let message = format!("verification failed with {} problem(s)", diagnostics.len());
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn describe(size: Option<u32>) -> String {
+    size.map_or("unbounded".to_string(), |size| format!("{} bytes", size))
+}
+
+fn do_verify(matches: &ArgMatches) -> Result<ExitStatus> {
+    let executable = load_executable(matches)?;
+    let vm = VM::new(stdin(), stdout(), executable, Arc::new(AtomicBool::new(false)))
+        .map_err(too_large)?;
+    let usage = vm.stack_usage().map_err(report)?;
+    println!("locals stack: {}", describe(usage.locals));
+    println!("return stack: {}", describe(usage.returns));
+    Ok(ExitStatus::Finished)
+}
+
+fn do_asm(matches: &ArgMatches) -> Result<ExitStatus> {
+    let mut source = String::new();
+    let mut source_file = File::open(matches.value_of("SOURCE").unwrap())?;
+    let _ = source_file.read_to_string(&mut source)?;
+
+    let executable = asm::assemble(&source)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+    let mut output_file = File::create(matches.value_of("OUTPUT").unwrap())?;
+    output_file.write_all(&executable)?;
+    Ok(ExitStatus::Finished)
+}
+
+fn do_disasm(matches: &ArgMatches) -> Result<ExitStatus> {
+    let executable = load_executable(matches)?;
+    print!("{}", asm::disassemble(&executable));
+    Ok(ExitStatus::Finished)
+}
+
+fn do_checked_main(matches: ArgMatches) -> Result<ExitStatus> {
+    let executable = load_executable(&matches)?;
 
     if matches.is_present("debug") {
         env::set_var("RUST_LOG", "lulzvm::vm=debug,error,info,warn,trace");
-        let _ = env_logger::init().unwrap();
+        env_logger::init().unwrap();
     }
 
     let termination_scheduled = Arc::new(AtomicBool::new(false));
@@ -51,8 +162,44 @@ fn do_checked_main(matches: ArgMatches) -> Result<()> {
         r.store(true, Ordering::Relaxed);
     });
 
-    let mut vm = VM::new(stdin(), stdout(), executable, termination_scheduled);
-    vm.run();
+    let input = AsyncReader::new(stdin());
+    let mut vm = VM::new(input, stdout(), executable, termination_scheduled).map_err(too_large)?;
+
+    if matches.is_present("verify") {
+        vm.verify().map_err(report)?;
+    }
+
+    if let Some(root) = matches.value_of("fs-root") {
+        let storage = HostDir::new(root)?;
+        vm.mount(Filesystem::new(Box::new(storage), Quota::default())?);
+    }
+
+    if matches.is_present("fused") {
+        vm.set_backend(Backend::Fused);
+    } else if matches.is_present("predecoded") {
+        vm.set_backend(Backend::Predecoded);
+    }
+
+    if matches.is_present("checked-heap") {
+        vm.set_heap_checked(true);
+    }
+
+    if matches.is_present("record") {
+        vm.record();
+    }
+
+    if let Some(journal_filename) = matches.value_of("replay") {
+        let mut journal_file = File::open(journal_filename)?;
+        let journal = Journal::load(&mut journal_file)?;
+        vm.replay(journal);
+    }
+
+    let status = vm.run();
+
+    if let Some(journal_filename) = matches.value_of("record") {
+        let mut journal_file = File::create(journal_filename)?;
+        vm.journal().save(&mut journal_file)?;
+    }
 
-    Ok(())
+    Ok(status)
 }
@@ -19,16 +19,29 @@ pub const REGISTERS_SIZE: Word = REGISTERS * WORD_SIZE;
 // pub const WORD_SIZE: usize = mem::size_of::<Word>();
 pub const WORD_SIZE: Word = 2;
 
+pub const HEAP_SIZE: Word = 4 * 1024;
+
+// unusable gap between the heap, the stacks and the event handlers
+pub const GUARD_SIZE: Word = 16;
+
+// extra memory is reachable through a window beyond the VM's own memory
+pub const BANK_WINDOW: Word = 0xe000;
+pub const BANK_SIZE: Word = 4 * 1024;
+pub const BANKS: u8 = 16;
+
 pub const LOCALS_STACK_SIZE: Word = 16 * 1024;
 
 pub const RETURN_STACK_SIZE: Word = 2 * 1024;
 
-pub const EVENT_HANDLERS: Word = 6;
+pub const EVENT_HANDLERS: Word = 32;
 pub const EVENT_HANDLERS_SIZE: Word = EVENT_HANDLERS * WORD_SIZE;
 
-pub const EVENT_QUEUE_SIZE: Word = 16;
+pub const EVENT_SIZE: Word = 2;
+pub const EVENT_QUEUE_SIZE: Word = 8 * EVENT_SIZE;
+
+pub const TIMERS: u8 = 4;
 
 pub const CODE_SIZE_OFFSET: Word = 0x0;
 pub const CODE_OFFSET: Word = CODE_SIZE_OFFSET + WORD_SIZE;
 
-pub type Registers = [Word; REGISTERS as usize];
+pub type RegisterFile = [Word; REGISTERS as usize];
@@ -2,6 +2,7 @@ extern crate byteorder;
 
 #[macro_use]
 extern crate log;
+#[cfg(test)]
 extern crate env_logger;
 
 extern crate stopwatch;
@@ -12,6 +13,8 @@ extern crate more_asserts;
 #[macro_use]
 pub mod utils;
 
+pub mod bench;
+
 pub mod config;
 
 pub mod vm;
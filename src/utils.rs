@@ -1,10 +1,4 @@
 use config::*;
-use env_logger;
-use std::io::{BufReader, BufWriter};
-use std::sync::Arc;
-use std::sync::atomic::AtomicBool;
-use vm::VM;
-use vm::memory::Memory;
 
 macro_rules! to_hex {
    ($data:expr, Word) => {
@@ -22,30 +16,53 @@ pub fn data_to_hex(data: DataSlice) -> String {
         .join(" ")
 }
 
-pub fn test_run(input: DataSlice,
-                mut executable: Data,
-                data_size: Word)
-                -> (Data, VM<BufReader<DataSlice>, BufWriter<Data>>) {
-    let _ = env_logger::init();
+#[cfg(test)]
+pub use self::testing::{test_run, test_vm};
 
-    let code_size = executable.len() as Word - CODE_OFFSET - data_size;
-    Memory::write_word(&mut executable, 0, code_size);
+/// VMs for the unit tests, the integration tests build their own with
+/// `VmBuilder`.
+#[cfg(test)]
+mod testing {
+    use config::*;
+    use env_logger;
+    use std::io::{self, BufReader, BufWriter};
+    use vm::VM;
+    use vm::builder::VmBuilder;
 
-    let input = BufReader::new(input);
+    /// The code size of `executable` is ignored, it's followed by
+    /// `data_size` bytes of data.
+    pub fn test_vm(input: DataSlice,
+                   executable: Data,
+                   data_size: Word)
+                   -> VM<BufReader<DataSlice>, BufWriter<Data>> {
+        let _ = env_logger::init();
 
-    let output: Data = vec![];
-    let output = BufWriter::new(output);
+        let code_end = executable.len() - data_size as usize;
 
-    let termination_scheduled = Arc::new(AtomicBool::new(false));
+        let output: Data = vec![];
 
-    let mut vm = VM::new(input, output, executable, termination_scheduled);
-    vm.run();
+        let mut vm = VmBuilder::new()
+            .code(&executable[(CODE_OFFSET as usize)..code_end])
+            .data(&executable[code_end..])
+            .input(BufReader::new(input))
+            .output(BufWriter::new(output))
+            .build()
+            .unwrap();
+        vm.set_diagnostics(io::sink());
+        vm
+    }
 
-    let output = vm.get_output_ref()
-        .get_ref()
-        .iter()
-        .map(|x| *x)
-        .collect::<Data>();
+    pub fn test_run(input: DataSlice,
+                    executable: Data,
+                    data_size: Word)
+                    -> (Data, VM<BufReader<DataSlice>, BufWriter<Data>>) {
+        let mut vm = test_vm(input, executable, data_size);
+        let _ = vm.run();
 
-    (output, vm)
+        let output = vm.get_output_ref()
+            .get_ref()
+            .to_vec();
+
+        (output, vm)
+    }
 }
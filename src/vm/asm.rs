@@ -0,0 +1,378 @@
+use config::*;
+use std::cmp;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use vm::events;
+use vm::memory::Memory;
+use vm::opcodes::*;
+
+/// A line of the source the assembler couldn't make sense of.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Error {
+    pub line: usize,
+    pub message: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Section {
+    Code,
+    Data,
+}
+
+enum Item<'a> {
+    Instruction(&'static Instruction, Vec<&'a str>),
+    Bytes(Data),
+}
+
+/// Translates source like `examples/hello.asm` into an executable: `.code`
+/// and `.data` sections, `label:` (or `label ascii "..."` for data)
+/// definitions, instructions by their mnemonics, `ascii` strings and
+/// `bytes` lists. Operands are numbers, labels (possibly in brackets) and
+/// event names, `;` starts a comment.
+pub fn assemble(source: &str) -> Result<Data, Error> {
+    let mut section = Section::Code;
+    let mut code_size = 0;
+    let mut data_size = 0;
+    let mut labels = HashMap::new();
+    let mut items = vec![];
+
+    for (index, line) in source.lines().enumerate() {
+        let number = index + 1;
+        let fail = |message: String| {
+            Error {
+                line: number,
+                message,
+            }
+        };
+
+        let mut line = strip_comment(line).trim();
+        match line {
+            "" => continue,
+            ".code" if section == Section::Data => return Err(fail("code after data".to_string())),
+            ".code" => {
+                section = Section::Code;
+                continue;
+            }
+            ".data" => {
+                section = Section::Data;
+                continue;
+            }
+            _ => (),
+        }
+
+        let (first, rest) = split_token(line);
+        let (second, _) = split_token(rest);
+        let label = if let Some(label) = first.strip_suffix(':') {
+            Some(label)
+        } else if !is_directive(first) && by_mnemonic(first).is_none() && is_directive(second) {
+            Some(first)
+        } else {
+            None
+        };
+
+        if let Some(label) = label {
+            if !is_identifier(label) {
+                return Err(fail(format!("invalid label {:?}", label)));
+            }
+
+            let address = match section {
+                Section::Code => CODE_OFFSET + code_size,
+                Section::Data => CODE_OFFSET + code_size + data_size,
+            };
+            if labels.insert(label, address).is_some() {
+                return Err(fail(format!("label {} is defined twice", label)));
+            }
+
+            line = rest;
+            if line.is_empty() {
+                continue;
+            }
+        }
+
+        let (word, rest) = split_token(line);
+        let item = match word {
+            "ascii" => Item::Bytes(parse_string(rest).map_err(fail)?),
+            "bytes" => {
+                let values = operands(rest)
+                    .iter()
+                    .map(|value| parse_number(value, 0xff))
+                    .collect::<Option<Data>>();
+                Item::Bytes(values.ok_or_else(|| fail(format!("invalid bytes {}", rest)))?)
+            }
+            _ => {
+                let instruction = by_mnemonic(&word.to_lowercase())
+                    .ok_or_else(|| fail(format!("unknown instruction {}", word)))?;
+                let operands = operands(rest);
+                if operands.len() != instruction.operands.len() {
+                    let message = format!("{} takes {} operand(s)",
+                                          instruction.mnemonic,
+                                          instruction.operands.len());
+                    return Err(fail(message));
+                }
+                Item::Instruction(instruction, operands)
+            }
+        };
+
+        let size = match item {
+            Item::Instruction(instruction, _) => instruction.size() as usize,
+            Item::Bytes(ref bytes) => bytes.len(),
+        };
+
+        if CODE_OFFSET as usize + code_size as usize + data_size as usize + size >
+           Word::MAX as usize {
+            return Err(fail("executable is too large".to_string()));
+        }
+
+        match section {
+            Section::Code => code_size += size as Word,
+            Section::Data => data_size += size as Word,
+        }
+
+        items.push((number, item));
+    }
+
+    let mut executable = vec![0x00; CODE_OFFSET as usize];
+    Memory::write_word(&mut executable, CODE_SIZE_OFFSET, code_size);
+
+    for (number, item) in items {
+        match item {
+            Item::Instruction(instruction, operands) => {
+                executable.push(instruction.opcode);
+                for (operand, text) in instruction.operands.iter().zip(operands) {
+                    let value = parse_operand(*operand, text, &labels).ok_or_else(|| {
+                            Error {
+                                line: number,
+                                message: format!("invalid operand {}", text),
+                            }
+                        })?;
+
+                    if operand.size() == 1 {
+                        executable.push(value as u8);
+                    } else {
+                        let end = executable.len();
+                        executable.resize(end + WORD_SIZE as usize, 0x00);
+                        Memory::write_word(&mut executable, end as Word, value);
+                    }
+                }
+            }
+            Item::Bytes(bytes) => executable.extend(bytes),
+        }
+    }
+
+    Ok(executable)
+}
+
+/// Turns an executable back into source the assembler accepts. Targets of
+/// jumps, calls and handlers get labels, code which can't be decoded is
+/// kept as `bytes`.
+pub fn disassemble(executable: DataSlice) -> String {
+    let header = CODE_OFFSET as usize;
+    let code_size = if executable.len() >= header {
+        Memory::read_word(executable, CODE_SIZE_OFFSET) as usize
+    } else {
+        0
+    };
+    let code_end = cmp::min(header + code_size, executable.len());
+
+    let mut decoded = vec![];
+    let mut offset = header;
+    while offset < code_end {
+        let instruction = match instruction(executable[offset]) {
+            Some(instruction) if offset + instruction.size() as usize <= code_end => instruction,
+            _ => break,
+        };
+
+        let operands = &executable[(offset + 1)..(offset + instruction.size() as usize)];
+        decoded.push((offset as Word, instruction, operands));
+        offset += instruction.size() as usize;
+    }
+    let undecoded = &executable[offset..code_end];
+
+    let mut targets = BTreeSet::new();
+    for &(_, instruction, operands) in &decoded {
+        let mut position = 0;
+        for operand in instruction.operands {
+            if *operand == Operand::Code || *operand == Operand::Handler {
+                let _ = targets.insert(Memory::read_word(operands, position));
+            }
+            position += operand.size();
+        }
+    }
+
+    let starts = decoded.iter().map(|&(address, _, _)| address).collect::<BTreeSet<Word>>();
+    let end = if undecoded.is_empty() {
+        Some(code_end as Word)
+    } else {
+        None
+    };
+    let labels = targets.into_iter()
+        .filter(|target| starts.contains(target) || Some(*target) == end)
+        .collect::<BTreeSet<Word>>();
+
+    let mut source = ".code\n".to_string();
+    for &(address, instruction, operands) in &decoded {
+        if labels.contains(&address) {
+            source += &format!("{}:\n", label(address));
+        }
+
+        source += &format!("    {}", instruction.mnemonic);
+        let mut position = 0;
+        for operand in instruction.operands {
+            let text = match *operand {
+                Operand::Byte => to_hex!(operands[position as usize]),
+                Operand::Event => {
+                    let event = operands[position as usize];
+                    events::name(event).map_or(to_hex!(event), |name| name.to_string())
+                }
+                Operand::Code | Operand::Handler | Operand::Data | Operand::Word => {
+                    let value = Memory::read_word(operands, position);
+                    if *operand != Operand::Data && *operand != Operand::Word &&
+                       labels.contains(&value) {
+                        label(value)
+                    } else {
+                        to_hex!(value, Word)
+                    }
+                }
+            };
+            source += &format!(" {}", text);
+            position += operand.size();
+        }
+        source += "\n";
+    }
+
+    source += &bytes(undecoded);
+    if labels.contains(&(code_end as Word)) {
+        source += &format!("{}:\n", label(code_end as Word));
+    }
+
+    let data = &executable[code_end..];
+    if !data.is_empty() {
+        source += ".data\n";
+        source += &bytes(data);
+    }
+
+    source
+}
+
+fn label(address: Word) -> String {
+    format!("l_{:04x}", address)
+}
+
+fn bytes(data: DataSlice) -> String {
+    data.chunks(16)
+        .map(|chunk| format!("    bytes {}\n", hex(chunk)))
+        .collect()
+}
+
+fn hex(data: DataSlice) -> String {
+    data.iter()
+        .map(|value| to_hex!(value))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &line[..index],
+            _ => (),
+        }
+    }
+    line
+}
+
+fn split_token(line: &str) -> (&str, &str) {
+    let line = line.trim();
+    match line.find(char::is_whitespace) {
+        Some(index) => (&line[..index], line[index..].trim()),
+        None => (line, ""),
+    }
+}
+
+fn operands(text: &str) -> Vec<&str> {
+    text.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|operand| !operand.is_empty())
+        .collect()
+}
+
+fn is_directive(word: &str) -> bool {
+    word == "ascii" || word == "bytes"
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+fn parse_number(text: &str, max: Word) -> Option<u8> {
+    parse_word(text).filter(|&value| value <= max).map(|value| value as u8)
+}
+
+fn parse_word(text: &str) -> Option<Word> {
+    match text.strip_prefix("0x") {
+        Some(digits) => Word::from_str_radix(digits, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+fn parse_operand(operand: Operand, text: &str, labels: &HashMap<&str, Word>) -> Option<Word> {
+    let text = text.strip_prefix('[')
+        .and_then(|text| text.strip_suffix(']'))
+        .unwrap_or(text);
+
+    match operand {
+        Operand::Byte => parse_number(text, 0xff).map(|value| value as Word),
+        Operand::Event => {
+            events::by_name(text)
+                .or_else(|| parse_number(text, 0xff))
+                .map(|value| value as Word)
+        }
+        Operand::Code | Operand::Handler | Operand::Data | Operand::Word => {
+            parse_word(text).or_else(|| labels.get(text).cloned())
+        }
+    }
+}
+
+fn parse_string(text: &str) -> Result<Data, String> {
+    if text.len() < 2 || !text.starts_with('"') || !text.ends_with('"') {
+        return Err(format!("invalid string {}", text));
+    }
+
+    let mut bytes = vec![];
+    let mut chars = text[1..(text.len() - 1)].chars();
+    while let Some(c) = chars.next() {
+        let value = match c {
+            '\\' => {
+                match chars.next() {
+                    Some('n') => b'\n',
+                    Some('t') => b'\t',
+                    Some('0') => 0x00,
+                    Some('\\') => b'\\',
+                    Some('"') => b'"',
+                    other => return Err(format!("invalid escape {:?}", other)),
+                }
+            }
+            c if c.is_ascii() => c as u8,
+            c => return Err(format!("{:?} isn't ASCII", c)),
+        };
+        bytes.push(value);
+    }
+
+    Ok(bytes)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
@@ -0,0 +1,121 @@
+use config::*;
+use vm::memory::Memory;
+use vm::opcodes::{self, Instruction};
+use vm::opcodes::*;
+
+const MAX_OPERANDS_SIZE: usize = 3;
+
+/// How the VM gets from the code bytes to the instruction to execute.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    /// Reads and looks up every instruction when it's executed.
+    Interpreter,
+    /// Decodes the whole code once before running it, see `Program`.
+    Predecoded,
+    /// Predecoded, and each of `FUSIONS` is executed in one step. A host
+    /// calling `VM::step` itself sees the PC inside of a sequence only at
+    /// the addresses passed to `VM::set_breakpoint`.
+    Fused,
+}
+
+/// Frequent sequences (comparisons with a constant, the loop of
+/// `hello.asm`), longest first. An event has to be delivered before the
+/// next instruction, so EMIT ends a sequence.
+const FUSIONS: &[&[u8]] = &[&[POP, INC, JMP],
+                            &[LOAD_OFFS, EMIT],
+                            &[PUSH, JE],
+                            &[PUSH, JNE],
+                            &[PUSH, JL],
+                            &[PUSH, JG],
+                            &[PUSH, JLE],
+                            &[PUSH, JGE],
+                            &[PUSH, ADD],
+                            &[PUSH, SUB],
+                            &[PUSH, MUL],
+                            &[PUSH, XOR],
+                            &[INC, JMP],
+                            &[DEC, JMP]];
+
+#[derive(Clone, Copy, Debug)]
+pub struct Decoded {
+    pub instruction: &'static Instruction,
+    operands: [u8; MAX_OPERANDS_SIZE],
+    /// How many of the following instructions are fused with this one.
+    pub fused: u8,
+}
+
+impl Decoded {
+    pub fn operands(&self) -> DataSlice<'_> {
+        &self.operands[..(self.instruction.operands_size() as usize)]
+    }
+}
+
+/// The instruction at every offset of the code, since a jump may land
+/// anywhere. Code is execute-only, so it never has to be decoded again.
+/// Unknown opcodes and instructions running past the end of the code are
+/// left to the interpreter.
+pub struct Program {
+    begin: Word,
+    instructions: Vec<Option<Decoded>>,
+}
+
+impl Program {
+    pub fn new(memory: &Memory, fuse: bool) -> Program {
+        let code = memory.code();
+        let instructions = (0..code.len())
+            .map(|offset| {
+                let instruction = opcodes::instruction(code[offset])?;
+                let end = offset + instruction.size() as usize;
+                if end > code.len() {
+                    return None;
+                }
+
+                let mut operands = [0; MAX_OPERANDS_SIZE];
+                operands[..(end - offset - 1)].copy_from_slice(&code[(offset + 1)..end]);
+                Some(Decoded {
+                    instruction,
+                    operands,
+                    fused: 0,
+                })
+            })
+            .collect();
+
+        let mut program = Program {
+            begin: memory.code_begin,
+            instructions,
+        };
+
+        if fuse {
+            for offset in 0..program.instructions.len() {
+                let address = program.begin + offset as Word;
+                if let Some(fusion) = FUSIONS.iter().find(|fusion| program.starts(address, fusion)) {
+                    if let Some(ref mut decoded) = program.instructions[offset] {
+                        decoded.fused = fusion.len() as u8 - 1;
+                    }
+                }
+            }
+        }
+
+        program
+    }
+
+    /// Whether the instructions from `address` on are `opcodes`.
+    fn starts(&self, mut address: Word, opcodes: &[u8]) -> bool {
+        for &opcode in opcodes {
+            match self.get(address) {
+                Some(decoded) if decoded.instruction.opcode == opcode => {
+                    address += decoded.instruction.size();
+                }
+                _ => return false,
+            }
+        }
+
+        true
+    }
+
+    pub fn get(&self, address: Word) -> Option<Decoded> {
+        address.checked_sub(self.begin)
+            .and_then(|offset| self.instructions.get(offset as usize))
+            .and_then(|decoded| *decoded)
+    }
+}
@@ -0,0 +1,125 @@
+use config::*;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use vm::VM;
+use vm::backend::Backend;
+use vm::memory::{Memory, ProgramTooLarge};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Config {
+    pub heap_size: Word,
+    /// See `VM::set_heap_checked`.
+    pub checked_heap: bool,
+    pub backend: Backend,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            heap_size: HEAP_SIZE,
+            checked_heap: false,
+            backend: Backend::Interpreter,
+        }
+    }
+}
+
+/// Puts a VM together from the code and data segments, so embedders don't
+/// have to lay out the executable themselves. Without `input` the program
+/// reads nothing, without `output` its output is discarded.
+pub struct VmBuilder<R: Read, W: Write> {
+    code: Data,
+    data: Data,
+    input: R,
+    output: W,
+    config: Config,
+    cancellation_token: Arc<AtomicBool>,
+}
+
+impl VmBuilder<io::Empty, io::Sink> {
+    pub fn new() -> Self {
+        VmBuilder {
+            code: vec![],
+            data: vec![],
+            input: io::empty(),
+            output: io::sink(),
+            config: Config::default(),
+            cancellation_token: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+impl Default for VmBuilder<io::Empty, io::Sink> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<R: Read, W: Write> VmBuilder<R, W> {
+    pub fn code(mut self, code: DataSlice) -> Self {
+        self.code = code.to_vec();
+        self
+    }
+
+    pub fn data(mut self, data: DataSlice) -> Self {
+        self.data = data.to_vec();
+        self
+    }
+
+    pub fn input<I: Read>(self, input: I) -> VmBuilder<I, W> {
+        VmBuilder {
+            code: self.code,
+            data: self.data,
+            input,
+            output: self.output,
+            config: self.config,
+            cancellation_token: self.cancellation_token,
+        }
+    }
+
+    pub fn output<O: Write>(self, output: O) -> VmBuilder<R, O> {
+        VmBuilder {
+            code: self.code,
+            data: self.data,
+            input: self.input,
+            output,
+            config: self.config,
+            cancellation_token: self.cancellation_token,
+        }
+    }
+
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Setting the flag asks the program to terminate, like Ctrl-C does
+    /// in `lulzvm`.
+    pub fn cancellation_token(mut self, token: Arc<AtomicBool>) -> Self {
+        self.cancellation_token = token;
+        self
+    }
+
+    /// The executable the VM is built from: the code size, the code and
+    /// the data.
+    pub fn executable(&self) -> Data {
+        assert_le!(self.code.len() + self.data.len(), (Word::MAX - CODE_OFFSET) as usize);
+        let mut executable = vec![0x00; CODE_OFFSET as usize];
+        Memory::write_word(&mut executable, CODE_SIZE_OFFSET, self.code.len() as Word);
+        executable.extend_from_slice(&self.code);
+        executable.extend_from_slice(&self.data);
+        executable
+    }
+
+    /// Fails if the program and the heap don't fit in memory.
+    pub fn build(self) -> Result<VM<R, W>, ProgramTooLarge> {
+        let size = CODE_OFFSET as usize + self.code.len() + self.data.len();
+        Memory::check_executable_size(size, self.config.heap_size)?;
+
+        let memory = Memory::from_executable(self.executable(), self.config.heap_size)?;
+        let mut vm = VM::with_memory(self.input, self.output, memory, self.cancellation_token);
+        vm.set_heap_checked(self.config.checked_heap);
+        vm.set_backend(self.config.backend);
+        Ok(vm)
+    }
+}
@@ -0,0 +1,74 @@
+use config::*;
+use std::io::{ErrorKind, Read, Write};
+use vm::devices::Device;
+use vm::input::AsyncReader;
+
+pub const CONSOLE_DATA: Word = 0;
+pub const CONSOLE_STATUS: Word = 1;
+
+// what reading CONSOLE_STATUS returns
+pub const CONSOLE_EMPTY: u8 = 0x00;
+pub const CONSOLE_READY: u8 = 0x01;
+pub const CONSOLE_EOF: u8 = 0x02;
+
+/// Reading offset 0 takes the next input byte (0x00 if there's none yet or
+/// the input has ended), offset 1 tells which of these it is. The input is
+/// read on a separate thread, so a blocking source never blocks the VM.
+/// Every written byte goes to `output`.
+pub struct Console<W: Write> {
+    input: AsyncReader,
+    output: W,
+    buffer: Option<u8>,
+    eof: bool,
+}
+
+impl<W: Write> Console<W> {
+    pub fn new<R: Read + Send + 'static>(input: R, output: W) -> Self {
+        Console {
+            input: AsyncReader::new(input),
+            output,
+            buffer: None,
+            eof: false,
+        }
+    }
+
+    fn poll(&mut self) {
+        if self.buffer.is_some() || self.eof {
+            return;
+        }
+
+        let mut buffer = [0; 1];
+        match self.input.read(&mut buffer) {
+            Ok(0) => self.eof = true,
+            Ok(_) => self.buffer = Some(buffer[0]),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock ||
+                          e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => {
+                error!("console input error: {}", e);
+                self.eof = true;
+            }
+        }
+    }
+}
+
+impl<W: Write> Device for Console<W> {
+    fn read(&mut self, offset: Word) -> u8 {
+        self.poll();
+        if offset == CONSOLE_STATUS {
+            match self.buffer {
+                Some(_) => CONSOLE_READY,
+                None if self.eof => CONSOLE_EOF,
+                None => CONSOLE_EMPTY,
+            }
+        } else {
+            self.buffer.take().unwrap_or(0x00)
+        }
+    }
+
+    fn write(&mut self, _: Word, value: u8) {
+        self.output.write_all(&[value]).unwrap();
+        if value == b'\n' {
+            self.output.flush().unwrap();
+        }
+    }
+}
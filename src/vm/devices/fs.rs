@@ -0,0 +1,447 @@
+use config::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+use std::rc::Rc;
+use vm::host::{HostContext, Signature};
+
+// SYSCALL ids, arguments are popped in the listed order
+pub const FS_OPEN: u8 = 0xf0;     // mode, name address -> fd
+pub const FS_CLOSE: u8 = 0xf1;    // fd -> status
+pub const FS_READ: u8 = 0xf2;     // fd, address, length -> count
+pub const FS_WRITE: u8 = 0xf3;    // fd, address, length -> count
+pub const FS_SEEK: u8 = 0xf4;     // fd, position -> status
+
+pub const FS_SYSCALLS: [(u8, Signature); 5] = [(FS_OPEN, Signature { pops: 3, pushes: 1 }),
+                                               (FS_CLOSE, Signature { pops: 1, pushes: 1 }),
+                                               (FS_READ, Signature { pops: 4, pushes: 1 }),
+                                               (FS_WRITE, Signature { pops: 4, pushes: 1 }),
+                                               (FS_SEEK, Signature { pops: 3, pushes: 1 })];
+
+pub const READ_MODE: u8 = 0x00;
+pub const WRITE_MODE: u8 = 0x01;  // truncates
+pub const APPEND_MODE: u8 = 0x02;
+
+pub const FS_OK: u8 = 0x00;
+pub const FS_ERROR: u8 = 0xff;
+
+// counts and descriptors stay below FS_ERROR
+pub const MAX_TRANSFER: u8 = 0xfe;
+pub const MAX_OPEN_FILES: usize = 0xff;
+
+pub const MAX_NAME_LENGTH: usize = 32;
+
+/// Where the files live. Names are already validated by `Filesystem`, they
+/// never contain path separators.
+pub trait Storage {
+    /// `None` if there's no such file.
+    fn load(&self, name: &str) -> Result<Option<Data>>;
+    fn store(&mut self, name: &str, data: DataSlice) -> Result<()>;
+    fn size(&self, name: &str) -> Result<usize>;
+
+    /// Total size of all files.
+    fn used(&self) -> Result<usize>;
+}
+
+/// Files directly inside a host directory, symlinks are refused.
+pub struct HostDir {
+    root: PathBuf,
+}
+
+impl HostDir {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Result<HostDir> {
+        let root = root.into();
+        if fs::metadata(&root)?.is_dir() {
+            Ok(HostDir { root })
+        } else {
+            Err(Error::new(ErrorKind::InvalidInput, "filesystem root is not a directory"))
+        }
+    }
+
+    fn path(&self, name: &str) -> Result<Option<PathBuf>> {
+        let path = self.root.join(name);
+        match fs::symlink_metadata(&path) {
+            Ok(ref metadata) if !metadata.is_file() => {
+                Err(Error::new(ErrorKind::PermissionDenied, "not a regular file"))
+            }
+            Ok(_) => Ok(Some(path)),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Storage for HostDir {
+    fn load(&self, name: &str) -> Result<Option<Data>> {
+        match self.path(name)? {
+            Some(path) => fs::read(path).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn store(&mut self, name: &str, data: DataSlice) -> Result<()> {
+        let _ = self.path(name)?;
+        fs::write(self.root.join(name), data)
+    }
+
+    fn size(&self, name: &str) -> Result<usize> {
+        match self.path(name)? {
+            Some(path) => Ok(fs::metadata(path)?.len() as usize),
+            None => Ok(0),
+        }
+    }
+
+    fn used(&self) -> Result<usize> {
+        let mut used = 0;
+        for entry in fs::read_dir(&self.root)? {
+            let metadata = entry?.metadata()?;
+            if metadata.is_file() {
+                used += metadata.len() as usize;
+            }
+        }
+        Ok(used)
+    }
+}
+
+/// Lets the host keep access to the storage it has mounted.
+impl<S: Storage> Storage for Rc<RefCell<S>> {
+    fn load(&self, name: &str) -> Result<Option<Data>> {
+        self.borrow().load(name)
+    }
+
+    fn store(&mut self, name: &str, data: DataSlice) -> Result<()> {
+        self.borrow_mut().store(name, data)
+    }
+
+    fn size(&self, name: &str) -> Result<usize> {
+        self.borrow().size(name)
+    }
+
+    fn used(&self) -> Result<usize> {
+        self.borrow().used()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    pub files: HashMap<String, Data>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn load(&self, name: &str) -> Result<Option<Data>> {
+        Ok(self.files.get(name).cloned())
+    }
+
+    fn store(&mut self, name: &str, data: DataSlice) -> Result<()> {
+        let _ = self.files.insert(name.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    fn size(&self, name: &str) -> Result<usize> {
+        Ok(self.files.get(name).map_or(0, |data| data.len()))
+    }
+
+    fn used(&self) -> Result<usize> {
+        Ok(self.files.values().map(|data| data.len()).sum())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quota {
+    pub open_files: usize,
+    pub file_size: usize,
+    pub total_size: usize,
+}
+
+impl Default for Quota {
+    fn default() -> Quota {
+        Quota {
+            open_files: 4,
+            file_size: 64 * 1024,
+            total_size: 1024 * 1024,
+        }
+    }
+}
+
+struct Handle {
+    name: String,
+    data: Data,
+    position: usize,
+    writable: bool,
+    dirty: bool,
+}
+
+/// Open files are kept in memory and stored back on close (or when the
+/// filesystem is dropped).
+pub struct Filesystem {
+    storage: Box<dyn Storage>,
+    quota: Quota,
+    handles: Vec<Option<Handle>>,
+}
+
+impl Filesystem {
+    pub fn new(storage: Box<dyn Storage>, quota: Quota) -> Result<Filesystem> {
+        if quota.open_files > MAX_OPEN_FILES {
+            return Err(Error::new(ErrorKind::InvalidInput, "too many open files allowed"));
+        }
+
+        let handles = (0..quota.open_files).map(|_| None).collect();
+        Ok(Filesystem {
+            storage,
+            quota,
+            handles,
+        })
+    }
+
+    pub fn open(&mut self, name: &str, mode: u8) -> Result<u8> {
+        if !is_valid_name(name) {
+            return Err(Error::new(ErrorKind::InvalidInput, "invalid file name"));
+        }
+
+        let fd = self.handles
+            .iter()
+            .position(|handle| handle.is_none())
+            .ok_or_else(|| Error::other("too many open files"))?;
+
+        // the handle closed last would overwrite what the others have written
+        let writing = self.handles
+            .iter()
+            .flatten()
+            .any(|handle| handle.writable && handle.name == name);
+        if writing && mode != READ_MODE {
+            return Err(Error::other("file is already open for writing"));
+        }
+
+        let handle = match mode {
+            READ_MODE => {
+                let data = self.storage
+                    .load(name)?
+                    .ok_or_else(|| Error::from(ErrorKind::NotFound))?;
+                Handle::new(name, data, 0, false)
+            }
+            WRITE_MODE => Handle::new(name, vec![], 0, true),
+            APPEND_MODE => {
+                let data = self.storage.load(name)?.unwrap_or_default();
+                let position = data.len();
+                Handle::new(name, data, position, true)
+            }
+            _ => return Err(Error::new(ErrorKind::InvalidInput, "unknown mode")),
+        };
+
+        self.handles[fd] = Some(handle);
+        Ok(fd as u8)
+    }
+
+    pub fn close(&mut self, fd: u8) -> Result<()> {
+        let result = self.flush(fd);
+        if let Some(handle) = self.handles.get_mut(fd as usize) {
+            *handle = None;
+        }
+        result
+    }
+
+    pub fn read(&mut self, fd: u8, length: usize) -> Result<Data> {
+        let handle = self.handle(fd)?;
+        let begin = handle.position.min(handle.data.len());
+        let end = (begin + length).min(handle.data.len());
+        handle.position = end;
+        Ok(handle.data[begin..end].to_vec())
+    }
+
+    pub fn write(&mut self, fd: u8, data: DataSlice) -> Result<()> {
+        let quota = self.quota;
+        let new_size = {
+            let handle = self.handle(fd)?;
+            if !handle.writable {
+                return Err(Error::new(ErrorKind::PermissionDenied, "opened for reading"));
+            }
+            let end = handle.position + data.len();
+            end.max(handle.data.len())
+        };
+
+        if new_size > quota.file_size || self.used_after(fd, new_size)? > quota.total_size {
+            return Err(Error::other("quota exceeded"));
+        }
+
+        let handle = self.handle(fd)?;
+        let begin = handle.position;
+        if handle.data.len() < begin + data.len() {
+            handle.data.resize(begin + data.len(), 0);
+        }
+        handle.data[begin..begin + data.len()].copy_from_slice(data);
+        handle.position += data.len();
+        handle.dirty = true;
+        Ok(())
+    }
+
+    pub fn seek(&mut self, fd: u8, position: usize) -> Result<()> {
+        let handle = self.handle(fd)?;
+        handle.position = position;
+        Ok(())
+    }
+
+    /// Runs the filesystem SYSCALL `id`, see `FS_SYSCALLS`.
+    pub fn syscall(&mut self, id: u8, ctx: &mut dyn HostContext) {
+        let status = match id {
+            FS_OPEN => {
+                let args = ctx.pop().and_then(|mode| ctx.pop_word().map(|ptr| (mode, ptr)));
+                match args.and_then(|(mode, ptr)| load_name(ctx, ptr).map(|name| (mode, name))) {
+                    Some((mode, name)) => self.open(&name, mode).unwrap_or_else(fail),
+                    None => return ctx.fault(),
+                }
+            }
+            FS_CLOSE => {
+                match ctx.pop() {
+                    Some(fd) => self.close(fd).map(|_| FS_OK).unwrap_or_else(fail),
+                    None => return ctx.fault(),
+                }
+            }
+            FS_READ => {
+                let (fd, ptr, length) = match pop_transfer(ctx) {
+                    Some(args) => args,
+                    None => return ctx.fault(),
+                };
+                match self.read(fd, length.min(MAX_TRANSFER) as usize) {
+                    Ok(data) => {
+                        for (i, value) in data.iter().enumerate() {
+                            if !ctx.store(ptr.wrapping_add(i as Word), *value) {
+                                return ctx.fault();
+                            }
+                        }
+                        data.len() as u8
+                    }
+                    Err(e) => fail(e),
+                }
+            }
+            FS_WRITE => {
+                let (fd, ptr, length) = match pop_transfer(ctx) {
+                    Some(args) => args,
+                    None => return ctx.fault(),
+                };
+                let length = length.min(MAX_TRANSFER);
+                let data: Option<Data> = (0..length as Word)
+                    .map(|i| ctx.load(ptr.wrapping_add(i)))
+                    .collect();
+                match data {
+                    Some(data) => self.write(fd, &data).map(|_| length).unwrap_or_else(fail),
+                    None => return ctx.fault(),
+                }
+            }
+            FS_SEEK => {
+                let args = ctx.pop().and_then(|fd| ctx.pop_word().map(|position| (fd, position)));
+                match args {
+                    Some((fd, position)) => {
+                        self.seek(fd, position as usize).map(|_| FS_OK).unwrap_or_else(fail)
+                    }
+                    None => return ctx.fault(),
+                }
+            }
+            _ => return ctx.fault(),
+        };
+
+        if !ctx.push(status) {
+            ctx.fault();
+        }
+    }
+
+    fn handle(&mut self, fd: u8) -> Result<&mut Handle> {
+        self.handles
+            .get_mut(fd as usize)
+            .and_then(|handle| handle.as_mut())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "bad file descriptor"))
+    }
+
+    /// How much the storage will hold once every open file is stored, with
+    /// the one of `fd` grown to `size`. Only one handle per file writes.
+    fn used_after(&self, fd: u8, size: usize) -> Result<usize> {
+        let mut used = self.storage.used()?;
+        for (i, handle) in self.handles.iter().enumerate() {
+            if let Some(ref handle) = *handle {
+                let pending = if i == fd as usize {
+                    size
+                } else if handle.dirty {
+                    handle.data.len()
+                } else {
+                    continue;
+                };
+                used = used.saturating_sub(self.storage.size(&handle.name)?) + pending;
+            }
+        }
+        Ok(used)
+    }
+
+    fn flush(&mut self, fd: u8) -> Result<()> {
+        let handle = self.handle(fd)?;
+        if handle.dirty {
+            handle.dirty = false;
+            let name = handle.name.clone();
+            let data = handle.data.clone();
+            self.storage.store(&name, &data)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for Filesystem {
+    fn drop(&mut self) {
+        for fd in 0..self.handles.len() {
+            if self.handles[fd].is_some() {
+                if let Err(e) = self.close(fd as u8) {
+                    error!("can't store file: {}", e);
+                }
+            }
+        }
+    }
+}
+
+impl Handle {
+    fn new(name: &str, data: Data, position: usize, writable: bool) -> Handle {
+        Handle {
+            name: name.to_string(),
+            data,
+            position,
+            writable,
+            dirty: writable,
+        }
+    }
+}
+
+/// Names are confined to a single directory level: letters, digits, `.`,
+/// `_` and `-`, not starting with `.`.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= MAX_NAME_LENGTH && !name.starts_with('.') &&
+    name.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-')
+}
+
+fn fail(e: Error) -> u8 {
+    debug!("filesystem error: {}", e);
+    FS_ERROR
+}
+
+fn pop_transfer(ctx: &mut dyn HostContext) -> Option<(u8, Word, u8)> {
+    let fd = ctx.pop()?;
+    let ptr = ctx.pop_word()?;
+    let length = ctx.pop()?;
+    Some((fd, ptr, length))
+}
+
+/// Names are NUL-terminated strings in the data segment.
+fn load_name(ctx: &mut dyn HostContext, ptr: Word) -> Option<String> {
+    let mut name = vec![];
+    for i in 0..(MAX_NAME_LENGTH + 1) as Word {
+        match ctx.load(ptr.wrapping_add(i))? {
+            0x00 => return String::from_utf8(name).ok(),
+            value => name.push(value),
+        }
+    }
+    String::from_utf8(name).ok()
+}
@@ -0,0 +1,128 @@
+use config::*;
+
+pub mod console;
+pub mod fs;
+pub mod random;
+pub mod timer;
+
+/// A peripheral the bytecode talks to either through IN/OUT ports or through
+/// a memory-mapped range. Offsets are relative to the beginning of the
+/// device's mapping.
+pub trait Device {
+    fn read(&mut self, offset: Word) -> u8;
+    fn write(&mut self, offset: Word, value: u8);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mapping {
+    Ports(u8, u8),
+    Memory(Word, Word),
+}
+
+impl Mapping {
+    fn overlaps(&self, other: &Mapping) -> bool {
+        match (*self, *other) {
+            (Mapping::Ports(a, a_count), Mapping::Ports(b, b_count)) => {
+                overlaps(a as u32, a_count as u32, b as u32, b_count as u32)
+            }
+            (Mapping::Memory(a, a_size), Mapping::Memory(b, b_size)) => {
+                overlaps(a as u32, a_size as u32, b as u32, b_size as u32)
+            }
+            _ => false,
+        }
+    }
+
+    fn port_offset(&self, port: u8) -> Option<Word> {
+        match *self {
+            Mapping::Ports(first, count) => offset(first as u32, count as u32, port as u32),
+            Mapping::Memory(_, _) => None,
+        }
+    }
+
+    fn memory_offset(&self, address: Word) -> Option<Word> {
+        match *self {
+            Mapping::Memory(begin, size) => offset(begin as u32, size as u32, address as u32),
+            Mapping::Ports(_, _) => None,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Bus {
+    devices: Vec<(Mapping, Box<dyn Device>)>,
+}
+
+impl Bus {
+    pub fn new() -> Bus {
+        Bus::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    pub fn attach(&mut self, mapping: Mapping, device: Box<dyn Device>) -> bool {
+        let taken = self.devices
+            .iter()
+            .any(|(other, _)| mapping.overlaps(other));
+
+        if taken {
+            false
+        } else {
+            debug!("attach device at {:?}", mapping);
+            self.devices.push((mapping, device));
+            true
+        }
+    }
+
+    pub fn is_mapped(&self, address: Word) -> bool {
+        self.devices
+            .iter()
+            .any(|(mapping, _)| mapping.memory_offset(address).is_some())
+    }
+
+    pub fn read_port(&mut self, port: u8) -> Option<u8> {
+        self.find(|mapping| mapping.port_offset(port))
+            .map(|(offset, device)| device.read(offset))
+    }
+
+    pub fn write_port(&mut self, port: u8, value: u8) -> bool {
+        self.find(|mapping| mapping.port_offset(port))
+            .map(|(offset, device)| device.write(offset, value))
+            .is_some()
+    }
+
+    pub fn read(&mut self, address: Word) -> Option<u8> {
+        self.find(|mapping| mapping.memory_offset(address))
+            .map(|(offset, device)| device.read(offset))
+    }
+
+    pub fn write(&mut self, address: Word, value: u8) -> bool {
+        self.find(|mapping| mapping.memory_offset(address))
+            .map(|(offset, device)| device.write(offset, value))
+            .is_some()
+    }
+
+    fn find<F>(&mut self, offset_of: F) -> Option<(Word, &mut Box<dyn Device>)>
+        where F: Fn(&Mapping) -> Option<Word>
+    {
+        self.devices
+            .iter_mut()
+            .filter_map(|&mut (ref mapping, ref mut device)| {
+                offset_of(mapping).map(|offset| (offset, device))
+            })
+            .next()
+    }
+}
+
+fn overlaps(a: u32, a_size: u32, b: u32, b_size: u32) -> bool {
+    a < b + b_size && b < a + a_size
+}
+
+fn offset(begin: u32, size: u32, index: u32) -> Option<Word> {
+    if index >= begin && index < begin + size {
+        Some((index - begin) as Word)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,32 @@
+use config::*;
+use vm::devices::Device;
+
+/// Xorshift pseudo-random source, writing a byte reseeds it.
+pub struct Random {
+    state: u32,
+}
+
+impl Random {
+    pub fn new(seed: u32) -> Random {
+        Random { state: Self::nonzero(seed) }
+    }
+
+    fn nonzero(seed: u32) -> u32 {
+        if seed == 0 { 0x2545_f491 } else { seed }
+    }
+}
+
+impl Device for Random {
+    fn read(&mut self, _: Word) -> u8 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        (x >> 24) as u8
+    }
+
+    fn write(&mut self, _: Word, value: u8) {
+        self.state = Self::nonzero(value as u32);
+    }
+}
@@ -0,0 +1,36 @@
+use config::*;
+use stopwatch::Stopwatch;
+use vm::devices::Device;
+
+/// Milliseconds since the device was attached (or last reset by a write),
+/// offset 0 is the low byte and offset 1 is the high byte.
+pub struct Timer {
+    stopwatch: Stopwatch,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer { stopwatch: Stopwatch::start_new() }
+    }
+}
+
+impl Default for Timer {
+    fn default() -> Timer {
+        Timer::new()
+    }
+}
+
+impl Device for Timer {
+    fn read(&mut self, offset: Word) -> u8 {
+        let elapsed = self.stopwatch.elapsed_ms() as Word;
+        if offset == 0 {
+            elapsed as u8
+        } else {
+            (elapsed >> 8) as u8
+        }
+    }
+
+    fn write(&mut self, _: Word, _: u8) {
+        self.stopwatch.restart();
+    }
+}
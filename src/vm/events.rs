@@ -1,3 +1,5 @@
+use config::*;
+
 pub const CLOCK: u8 = 0x00;
 
 pub const INPUT: u8 = 0x01;
@@ -7,6 +9,62 @@ pub const TERMINATE: u8 = 0x03;
 pub const SEGFAULT: u8 = 0x04;
 pub const UNKNOWN_ERROR: u8 = 0x05;
 
+// TERMINATE argument when the host (e.g. Ctrl-C) asks the program to stop
+pub const INTERRUPTED: u8 = 130;
+
+// one event per timer, 0x06..0x09
+pub const TIMER: u8 = 0x06;
+
+pub const INPUT_EOF: u8 = 0x0a;
+
+// 0x0b..0x0f are reserved for future built-in events
+pub const USER_EVENTS_BEGIN: u8 = 0x10;
+
+pub type Priority = u8;
+
+pub const LOW_PRIORITY: Priority = 0x00;
+pub const NORMAL_PRIORITY: Priority = 0x01;
+pub const HIGH_PRIORITY: Priority = 0x02;
+
+pub fn is_valid(id: u8) -> bool {
+    (id as Word) < EVENT_HANDLERS
+}
+
+pub fn is_user_defined(id: u8) -> bool {
+    id >= USER_EVENTS_BEGIN && is_valid(id)
+}
+
 pub fn is_critical(id: u8) -> bool {
-    id >= TERMINATE
+    matches!(id, TERMINATE | SEGFAULT | UNKNOWN_ERROR)
+}
+
+const NAMES: &[(u8, &str)] = &[(CLOCK, "clock"),
+                                (INPUT, "input"),
+                                (OUTPUT, "output"),
+                                (TERMINATE, "terminate"),
+                                (SEGFAULT, "segfault"),
+                                (UNKNOWN_ERROR, "unknown_error"),
+                                (TIMER, "timer0"),
+                                (TIMER + 1, "timer1"),
+                                (TIMER + 2, "timer2"),
+                                (TIMER + 3, "timer3"),
+                                (INPUT_EOF, "input_eof")];
+
+/// Name of a built-in event as the assembler knows it.
+pub fn name(id: u8) -> Option<&'static str> {
+    NAMES.iter().find(|&&(event, _)| event == id).map(|&(_, name)| name)
+}
+
+pub fn by_name(name: &str) -> Option<u8> {
+    NAMES.iter().find(|&&(_, event_name)| event_name == name).map(|&(event, _)| event)
+}
+
+/// Saved on handler entry and restored by RETI (or by the RET that
+/// unwinds the handler's return address, which keeps the locals stack).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HandlerFrame {
+    pub rp: Word,
+    pub sp: Word,
+    pub waiting: bool,
+    pub events_enabled: bool,
 }
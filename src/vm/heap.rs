@@ -0,0 +1,78 @@
+use config::*;
+use std::collections::BTreeMap;
+
+/// First-fit allocator over `begin..end`, only allocated blocks are kept
+/// (address to size), free space is whatever lies between them.
+#[derive(Debug)]
+pub struct Heap {
+    begin: Word,
+    end: Word,
+    blocks: BTreeMap<Word, Word>,
+    checked: bool,
+}
+
+impl Heap {
+    pub fn new(begin: Word, end: Word) -> Heap {
+        Heap {
+            begin,
+            end,
+            blocks: BTreeMap::new(),
+            checked: false,
+        }
+    }
+
+    /// In checked mode only allocated blocks are accessible, otherwise the
+    /// whole heap is.
+    pub fn set_checked(&mut self, checked: bool) {
+        self.checked = checked;
+    }
+
+    pub fn alloc(&mut self, size: Word) -> Option<Word> {
+        if size == 0 {
+            return None;
+        }
+
+        let mut candidate = self.begin;
+        for (&begin, &block_size) in &self.blocks {
+            if begin - candidate >= size {
+                break;
+            }
+            candidate = begin + block_size;
+        }
+
+        if self.end - candidate >= size {
+            let _ = self.blocks.insert(candidate, size);
+            Some(candidate)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the size of the freed block, `None` if `ptr` isn't the
+    /// beginning of an allocated one.
+    pub fn free(&mut self, ptr: Word) -> Option<Word> {
+        self.blocks.remove(&ptr)
+    }
+
+    /// Marks a block as allocated again, used to undo `free`.
+    pub fn restore(&mut self, ptr: Word, size: Word) {
+        let _ = self.blocks.insert(ptr, size);
+    }
+
+    pub fn contains(&self, index: Word) -> bool {
+        index >= self.begin && index < self.end
+    }
+
+    pub fn is_accessible(&self, index: Word) -> bool {
+        if !self.contains(index) {
+            false
+        } else if !self.checked {
+            true
+        } else {
+            match self.blocks.range(..(index + 1)).next_back() {
+                Some((&begin, &size)) => index - begin < size,
+                None => false,
+            }
+        }
+    }
+}
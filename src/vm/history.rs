@@ -0,0 +1,110 @@
+use config::*;
+use std::collections::VecDeque;
+use vm::events::HandlerFrame;
+use vm::registers::Register;
+use vm::status::ExitStatus;
+use vm::timers::Timer;
+use std::mem;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Change {
+    Register(Register, Word),
+    Memory(Word, u8),
+    Waiting(bool),
+    Terminated(Option<ExitStatus>),
+    ClockStep(u8),
+    EventsEnabled(bool),
+    EventMask(u8, bool),
+    FramePushed,
+    FramePopped(HandlerFrame),
+    Timer(u8, Option<Timer>),
+    Alloc(Word),
+    Free(Word, Word),
+    Bank(u8),
+    /// A byte taken from the input, `None` for the end of it.
+    Input(Option<u8>),
+    InputBuffer(Option<u8>),
+}
+
+/// Everything a single VM step has overwritten, so it can be undone.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Step {
+    pub steps: u64,
+    pub opcode: Option<u8>,
+    pub changes: Vec<Change>,
+}
+
+impl Step {
+    pub fn writes_to(&self, address: Word) -> bool {
+        self.changes.iter().any(|change| match *change {
+            Change::Memory(index, _) => index == address,
+            _ => false,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct History {
+    capacity: usize,
+    steps: VecDeque<Step>,
+    current: Step,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> History {
+        History {
+            capacity,
+            steps: VecDeque::with_capacity(capacity),
+            current: Step::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    pub fn begin(&mut self, steps: u64) {
+        self.current = Step {
+            steps,
+            opcode: None,
+            changes: vec![],
+        };
+    }
+
+    pub fn track(&mut self, change: Change) {
+        self.current.changes.push(change);
+    }
+
+    pub fn commit(&mut self, opcode: Option<u8>) {
+        self.current.opcode = opcode;
+
+        if !self.current.changes.is_empty() && self.capacity > 0 {
+            if self.steps.len() == self.capacity {
+                let _ = self.steps.pop_front();
+            }
+
+            let step = mem::take(&mut self.current);
+            self.steps.push_back(step);
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<Step> {
+        self.steps.pop_back()
+    }
+
+    /// How many steps have to be undone to revert the most recent step
+    /// matching `found`.
+    pub fn position<F>(&self, found: F) -> Option<usize>
+        where F: Fn(&Step) -> bool
+    {
+        self.steps
+            .iter()
+            .rev()
+            .position(found)
+            .map(|index| index + 1)
+    }
+}
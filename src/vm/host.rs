@@ -0,0 +1,40 @@
+use config::*;
+
+/// What a host function registered with `VM::register_host_fn` can do to the
+/// VM that called it. Words travel over the locals stack with their low byte
+/// on top.
+pub trait HostContext {
+    fn pop(&mut self) -> Option<u8>;
+    fn push(&mut self, value: u8) -> bool;
+
+    fn pop_word(&mut self) -> Option<Word> {
+        let low = self.pop()?;
+        let high = self.pop()?;
+        Some(((high as Word) << 8) | low as Word)
+    }
+
+    fn push_word(&mut self, value: Word) -> bool {
+        self.push((value >> 8) as u8) && self.push(value as u8)
+    }
+
+    /// Reads a byte of the data segment, `address` is absolute.
+    fn load(&self, address: Word) -> Option<u8>;
+
+    /// Writes a byte of the data segment, `address` is absolute.
+    fn store(&mut self, address: Word, value: u8) -> bool;
+
+    fn emit(&mut self, event: u8, argument: u8);
+
+    /// Raises SEGFAULT.
+    fn fault(&mut self);
+}
+
+pub type HostFn = Box<dyn FnMut(&mut dyn HostContext)>;
+
+/// How many bytes a host function pops from the locals stack and pushes
+/// onto it, which is all the verifier knows about it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Signature {
+    pub pops: u8,
+    pub pushes: u8,
+}
@@ -0,0 +1,70 @@
+use std::io::{Error, ErrorKind, Read, Result};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+
+/// Reads from a blocking source on a separate thread, so the VM can poll
+/// it: `read` fails with `WouldBlock` while there's nothing to read yet
+/// and returns 0 bytes at the end of the input.
+pub struct AsyncReader {
+    receiver: Receiver<u8>,
+    eof: bool,
+}
+
+impl AsyncReader {
+    pub fn new<R: Read + Send + 'static>(mut input: R) -> AsyncReader {
+        let (sender, receiver) = channel();
+
+        let _ = thread::spawn(move || {
+            let mut buffer = [0; 1];
+            loop {
+                match input.read(&mut buffer) {
+                    Ok(0) => break,
+                    Ok(_) => {
+                        if sender.send(buffer[0]).is_err() {
+                            break;
+                        }
+                    }
+                    Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+                    Err(e) => {
+                        error!("input error: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        AsyncReader {
+            receiver,
+            eof: false,
+        }
+    }
+}
+
+impl Read for AsyncReader {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        if self.eof || buffer.is_empty() {
+            return Ok(0);
+        }
+
+        let mut count = 0;
+        while count < buffer.len() {
+            match self.receiver.try_recv() {
+                Ok(value) => {
+                    buffer[count] = value;
+                    count += 1;
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.eof = count == 0;
+                    break;
+                }
+            }
+        }
+
+        if count == 0 && !self.eof {
+            Err(Error::new(ErrorKind::WouldBlock, "no input yet"))
+        } else {
+            Ok(count)
+        }
+    }
+}
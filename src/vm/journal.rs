@@ -0,0 +1,170 @@
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use config::*;
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+const INPUT_RECORD: u8 = 0x00;
+const CLOCK_RECORD: u8 = 0x01;
+const TERMINATE_RECORD: u8 = 0x02;
+const TIMER_RECORD: u8 = 0x03;
+const EOF_RECORD: u8 = 0x04;
+
+// step, kind and value
+const RECORD_SIZE: usize = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Off,
+    Record,
+    Replay,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Record {
+    Input(u8),
+    Clock,
+    Terminate,
+    Timer(u8),
+    Eof,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Entry {
+    pub step: u64,
+    pub record: Record,
+}
+
+/// Nondeterministic inputs of a single run, each tagged with the number of
+/// instructions executed before it happened.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Journal {
+    entries: VecDeque<Entry>,
+}
+
+impl Journal {
+    pub fn new() -> Journal {
+        Journal::default()
+    }
+
+    pub fn entries(&self) -> &VecDeque<Entry> {
+        &self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn push(&mut self, step: u64, record: Record) {
+        debug!("journal push step={} record={:?}", step, record);
+        self.entries.push_back(Entry { step, record });
+    }
+
+    pub fn next_input(&mut self, step: u64) -> Option<u8> {
+        match self.take(step, |record| matches!(record, Record::Input(_))) {
+            Some(Record::Input(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn next_eof(&mut self, step: u64) -> bool {
+        self.take(step, |record| record == Record::Eof).is_some()
+    }
+
+    pub fn next_clock(&mut self, step: u64) -> bool {
+        self.take(step, |record| record == Record::Clock).is_some()
+    }
+
+    pub fn next_termination(&mut self, step: u64) -> bool {
+        self.take(step, |record| record == Record::Terminate).is_some()
+    }
+
+    pub fn next_timer(&mut self, step: u64) -> Option<u8> {
+        match self.take(step, |record| matches!(record, Record::Timer(_))) {
+            Some(Record::Timer(id)) => Some(id),
+            _ => None,
+        }
+    }
+
+    pub fn load<R: Read>(input: &mut R) -> Result<Journal> {
+        let mut journal = Journal::new();
+
+        loop {
+            let mut buffer = [0; RECORD_SIZE];
+            let length = read_record(input, &mut buffer)?;
+            if length == 0 {
+                break;
+            } else if length < RECORD_SIZE {
+                return Err(Error::new(ErrorKind::InvalidData, "truncated journal record"));
+            }
+
+            let mut record = &buffer[..];
+            let step = record.read_u64::<Endian>()?;
+            let kind = record.read_u8()?;
+            let value = record.read_u8()?;
+            let record = match kind {
+                INPUT_RECORD => Record::Input(value),
+                CLOCK_RECORD => Record::Clock,
+                TERMINATE_RECORD => Record::Terminate,
+                TIMER_RECORD => Record::Timer(value),
+                EOF_RECORD => Record::Eof,
+                _ => {
+                    return Err(Error::new(ErrorKind::InvalidData,
+                                          "unknown journal record"))
+                }
+            };
+
+            journal.push(step, record);
+        }
+
+        Ok(journal)
+    }
+
+    pub fn save<W: Write>(&self, output: &mut W) -> Result<()> {
+        for entry in &self.entries {
+            let (kind, value) = match entry.record {
+                Record::Input(value) => (INPUT_RECORD, value),
+                Record::Clock => (CLOCK_RECORD, 0x00),
+                Record::Terminate => (TERMINATE_RECORD, 0x00),
+                Record::Timer(id) => (TIMER_RECORD, id),
+                Record::Eof => (EOF_RECORD, 0x00),
+            };
+
+            output.write_u64::<Endian>(entry.step)?;
+            output.write_u8(kind)?;
+            output.write_u8(value)?;
+        }
+
+        output.flush()
+    }
+
+    fn take<F>(&mut self, step: u64, matches: F) -> Option<Record>
+        where F: Fn(Record) -> bool
+    {
+        let found = match self.entries.front() {
+            Some(entry) => entry.step <= step && matches(entry.record),
+            None => false,
+        };
+
+        if found {
+            self.entries.pop_front().map(|entry| entry.record)
+        } else {
+            None
+        }
+    }
+}
+
+/// Reads until `buffer` is full or the input ends, returns how many bytes
+/// have been read.
+fn read_record<R: Read>(input: &mut R, buffer: &mut [u8]) -> Result<usize> {
+    let mut length = 0;
+    while length < buffer.len() {
+        match input.read(&mut buffer[length..]) {
+            Ok(0) => break,
+            Ok(count) => length += count,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(length)
+}
@@ -1,6 +1,60 @@
 use byteorder::ByteOrder;
 use config::*;
+use std::cell::RefCell;
 use std::cmp;
+use std::fmt;
+use std::mem;
+use vm::devices::{Bus, Device, Mapping};
+use vm::heap::Heap;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Region {
+    Header,
+    Code,
+    Data,
+    Heap,
+    LocalsStack,
+    ReturnStack,
+    EventHandlers,
+    EventQueue,
+    Guard,
+    Bank,
+    Device,
+    Unmapped,
+}
+
+/// What the program itself may do with a region, everything but code, data,
+/// heap and devices is only accessed by the VM.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Region {
+    pub fn permissions(&self) -> Permissions {
+        let (read, write, execute) = match *self {
+            Region::Code => (false, false, true),
+            Region::Data | Region::Heap | Region::Bank | Region::Device => (true, true, false),
+            _ => (false, false, false),
+        };
+
+        Permissions {
+            read,
+            write,
+            execute,
+        }
+    }
+}
+
+/// The executable, the heap and the VM's own memory don't fit below
+/// `BANK_WINDOW`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProgramTooLarge {
+    pub size: usize,
+    pub max_size: usize,
+}
 
 pub struct Memory {
     pub raw: Data,
@@ -13,6 +67,9 @@ pub struct Memory {
     pub data_begin: Word,
     pub data_end: Word,
 
+    pub heap_begin: Word,
+    pub heap_end: Word,
+
     pub locals_stack_begin: Word,
     pub locals_stack_end: Word,
 
@@ -24,72 +81,117 @@ pub struct Memory {
 
     pub event_queue_begin: Word,
     pub event_queue_end: Word,
+
+    writes: Option<Vec<(Word, u8)>>,
+
+    bus: RefCell<Bus>,
+
+    pub heap: Heap,
+
+    banks: Data,
+    bank: u8,
 }
 
 impl Memory {
-    pub fn from_executable(mut executable: Data) -> Memory {
+    /// How large an executable fits next to a heap of `heap_size`.
+    pub fn max_executable_size(heap_size: Word) -> usize {
+        let overhead = heap_size as usize + LOCALS_STACK_SIZE as usize +
+                       RETURN_STACK_SIZE as usize + EVENT_HANDLERS_SIZE as usize +
+                       EVENT_QUEUE_SIZE as usize + 3 * GUARD_SIZE as usize;
+        (BANK_WINDOW as usize).saturating_sub(overhead)
+    }
+
+    pub fn check_executable_size(size: usize, heap_size: Word) -> Result<(), ProgramTooLarge> {
+        let max_size = Self::max_executable_size(heap_size);
+        if size > max_size {
+            Err(ProgramTooLarge { size, max_size })
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn from_executable(mut executable: Data,
+                           heap_size: Word)
+                           -> Result<Memory, ProgramTooLarge> {
+        Self::check_executable_size(executable.len(), heap_size)?;
+
         let executable_size = executable.len() as Word;
-        let new_size = executable_size + LOCALS_STACK_SIZE + RETURN_STACK_SIZE +
-                       EVENT_HANDLERS_SIZE + EVENT_QUEUE_SIZE;
+        let new_size = executable_size + heap_size + LOCALS_STACK_SIZE + RETURN_STACK_SIZE +
+                       EVENT_HANDLERS_SIZE + EVENT_QUEUE_SIZE + 3 * GUARD_SIZE;
         executable.resize(new_size as usize, 0);
 
         let code_size = Self::read_word(&executable, CODE_SIZE_OFFSET);
         let code_begin = CODE_OFFSET;
         let code_end = CODE_OFFSET + code_size;
 
-        let locals_stack_begin = executable_size;
+        let heap_begin = executable_size;
+        let heap_end = heap_begin + heap_size;
+
+        let locals_stack_begin = heap_end + GUARD_SIZE;
         let locals_stack_end = locals_stack_begin + LOCALS_STACK_SIZE;
 
-        let return_stack_begin = locals_stack_end;
+        let return_stack_begin = locals_stack_end + GUARD_SIZE;
         let return_stack_end = return_stack_begin + RETURN_STACK_SIZE;
 
         let data_begin = cmp::min(CODE_OFFSET + code_size, executable_size);
         let data_end = executable_size;
 
-        let event_handlers_begin = return_stack_end;
-        let event_handlers_end = return_stack_end + EVENT_HANDLERS_SIZE;
+        let event_handlers_begin = return_stack_end + GUARD_SIZE;
+        let event_handlers_end = event_handlers_begin + EVENT_HANDLERS_SIZE;
 
         let event_queue_begin = event_handlers_end;
         let event_queue_end = event_handlers_end + EVENT_QUEUE_SIZE;
 
-        Memory {
+        Ok(Memory {
             raw: executable,
 
-            executable_size: executable_size,
+            executable_size,
 
-            code_begin: code_begin,
-            code_end: code_end,
+            code_begin,
+            code_end,
 
-            data_begin: data_begin,
-            data_end: data_end,
+            data_begin,
+            data_end,
 
-            locals_stack_begin: locals_stack_begin,
-            locals_stack_end: locals_stack_end,
+            heap_begin,
+            heap_end,
 
-            return_stack_begin: return_stack_begin,
-            return_stack_end: return_stack_end,
+            locals_stack_begin,
+            locals_stack_end,
 
-            event_handlers_begin: event_handlers_begin,
-            event_handlers_end: event_handlers_end,
+            return_stack_begin,
+            return_stack_end,
 
-            event_queue_begin: event_queue_begin,
-            event_queue_end: event_queue_end,
-        }
+            event_handlers_begin,
+            event_handlers_end,
+
+            event_queue_begin,
+            event_queue_end,
+
+            writes: None,
+
+            bus: RefCell::new(Bus::new()),
+
+            heap: Heap::new(heap_begin, heap_end),
+
+            banks: vec![0; BANKS as usize * BANK_SIZE as usize],
+            bank: 0,
+        })
     }
 
-    pub fn code(&self) -> DataSlice {
+    pub fn code(&self) -> DataSlice<'_> {
         let begin = self.code_begin as usize;
         let end = self.code_end as usize;
         &self.raw[begin..end]
     }
 
-    pub fn data(&self) -> DataSlice {
+    pub fn data(&self) -> DataSlice<'_> {
         let begin = self.data_begin as usize;
         let end = self.data_end as usize;
         &self.raw[begin..end]
     }
 
-    pub fn locals_stack(&self, sp: Word) -> DataSlice {
+    pub fn locals_stack(&self, sp: Word) -> DataSlice<'_> {
         assert_ge!(sp, self.locals_stack_begin);
         assert_le!(sp, self.locals_stack_end);
         let sp = sp as usize;
@@ -97,7 +199,7 @@ impl Memory {
         &self.raw[sp..locals_stack_end]
     }
 
-    pub fn return_stack(&self, rp: Word) -> DataSlice {
+    pub fn return_stack(&self, rp: Word) -> DataSlice<'_> {
         assert_ge!(rp, self.return_stack_begin);
         assert_le!(rp, self.return_stack_end);
         let rp = rp as usize;
@@ -127,7 +229,7 @@ impl Memory {
         self.put_word(offset, handler);
     }
 
-    pub fn event_queue(&self, ep: Word, ee: Word) -> DataSlice {
+    pub fn event_queue(&self, ep: Word, ee: Word) -> DataSlice<'_> {
         assert_ge!(ep, self.event_queue_begin);
         assert_le!(ep, self.event_queue_end);
         assert_gt!(ee, self.event_queue_begin);
@@ -145,13 +247,107 @@ impl Memory {
         index >= self.data_begin && index < self.data_end
     }
 
+    pub fn region(&self, index: Word) -> Region {
+        let regions = [(0, self.code_begin, Region::Header),
+                       (self.code_begin, self.code_end, Region::Code),
+                       (self.data_begin, self.data_end, Region::Data),
+                       (self.heap_begin, self.heap_end, Region::Heap),
+                       (self.locals_stack_begin, self.locals_stack_end, Region::LocalsStack),
+                       (self.return_stack_begin, self.return_stack_end, Region::ReturnStack),
+                       (self.event_handlers_begin, self.event_handlers_end, Region::EventHandlers),
+                       (self.event_queue_begin, self.event_queue_end, Region::EventQueue)];
+
+        match regions.iter().find(|&&(begin, end, _)| index >= begin && index < end) {
+            Some(&(_, _, region)) => region,
+            None if (index as usize) < self.raw.len() => Region::Guard,
+            None if self.is_in_bank_window(index) => Region::Bank,
+            None if self.is_mapped(index) => Region::Device,
+            None => Region::Unmapped,
+        }
+    }
+
+    /// Whether the program may read and write `index` (heap memory has to
+    /// be allocated in checked mode).
+    pub fn is_accessible(&self, index: Word) -> bool {
+        let permissions = self.region(index).permissions();
+        let accessible = permissions.read && permissions.write;
+        if accessible && self.heap.contains(index) {
+            self.heap.is_accessible(index)
+        } else {
+            accessible
+        }
+    }
+
+    pub fn is_in_bank_window(&self, index: Word) -> bool {
+        let index = index as usize;
+        let begin = BANK_WINDOW as usize;
+        index >= begin && index < begin + BANK_SIZE as usize
+    }
+
+    /// The bank currently mapped into the window.
+    pub fn bank(&self) -> u8 {
+        self.bank
+    }
+
+    pub fn set_bank(&mut self, bank: u8) {
+        debug!("set bank={}", to_hex!(bank));
+        assert_gt!(BANKS, bank);
+        self.bank = bank;
+    }
+
+    pub fn is_in_heap(&self, index: Word) -> bool {
+        self.heap.is_accessible(index)
+    }
+
+    pub fn is_mapped(&self, index: Word) -> bool {
+        let bus = self.bus.borrow();
+        !bus.is_empty() && bus.is_mapped(index)
+    }
+
+    /// Memory-mapped ranges have to lie beyond the VM's own memory and
+    /// outside of the bank window.
+    pub fn attach_device(&mut self, mapping: Mapping, device: Box<dyn Device>) -> bool {
+        if let Mapping::Memory(begin, size) = mapping {
+            let end = begin as usize + size as usize;
+            let window_begin = BANK_WINDOW as usize;
+            let window_end = window_begin + BANK_SIZE as usize;
+            if (begin as usize) < self.raw.len() || end > Word::MAX as usize + 1 ||
+               (begin as usize) < window_end && end > window_begin {
+                return false;
+            }
+        }
+
+        self.bus.borrow_mut().attach(mapping, device)
+    }
+
+    pub fn read_port(&self, port: u8) -> Option<u8> {
+        self.bus.borrow_mut().read_port(port)
+    }
+
+    pub fn write_port(&mut self, port: u8, value: u8) -> bool {
+        self.bus.borrow_mut().write_port(port, value)
+    }
+
     pub fn get(&self, index: Word) -> u8 {
-        self.raw[index as usize]
+        if self.is_mapped(index) {
+            if let Some(value) = self.bus.borrow_mut().read(index) {
+                return value;
+            }
+        }
+
+        self.peek(index)
     }
 
     pub fn put(&mut self, index: Word, value: u8) {
         debug!("put address={} value={}", to_hex!(index), to_hex!(value));
-        self.raw[index as usize] = value;
+        debug_assert!(self.region(index) != Region::Guard);
+        debug_assert!(self.region(index) != Region::Code);
+        if self.is_mapped(index) && self.bus.borrow_mut().write(index, value) {
+            return;
+        }
+
+        self.track_write(index);
+        self.set(index, value);
     }
 
     pub fn get_word(&self, index: Word) -> Word {
@@ -159,9 +355,68 @@ impl Memory {
     }
 
     pub fn put_word(&mut self, index: Word, value: Word) {
+        debug_assert!(self.region(index) != Region::Guard);
+        debug_assert!(self.region(index + 1) != Region::Guard);
+        self.track_write(index);
+        self.track_write(index + 1);
         Self::write_word(&mut self.raw, index, value)
     }
 
+    /// Starts remembering the previous value of every byte overwritten by
+    /// `put` and `put_word`.
+    pub fn track_writes(&mut self) {
+        if self.writes.is_none() {
+            self.writes = Some(vec![]);
+        }
+    }
+
+    pub fn take_writes(&mut self) -> Vec<(Word, u8)> {
+        match self.writes {
+            Some(ref mut writes) => mem::take(writes),
+            None => vec![],
+        }
+    }
+
+    /// Writes a byte back without tracking it.
+    pub fn restore(&mut self, index: Word, value: u8) {
+        debug!("restore address={} value={}",
+               to_hex!(index),
+               to_hex!(value));
+        self.set(index, value);
+    }
+
+    fn set(&mut self, index: Word, value: u8) {
+        if self.is_in_bank_window(index) {
+            let offset = self.bank_offset(index);
+            self.banks[offset] = value;
+        } else {
+            self.raw[index as usize] = value;
+        }
+    }
+
+    /// Writes to the window are undone while the same bank is selected,
+    /// since switching banks is undone after them.
+    fn track_write(&mut self, index: Word) {
+        if self.writes.is_some() {
+            let value = self.peek(index);
+            if let Some(ref mut writes) = self.writes {
+                writes.push((index, value));
+            }
+        }
+    }
+
+    fn peek(&self, index: Word) -> u8 {
+        if self.is_in_bank_window(index) {
+            self.banks[self.bank_offset(index)]
+        } else {
+            self.raw[index as usize]
+        }
+    }
+
+    fn bank_offset(&self, index: Word) -> usize {
+        self.bank as usize * BANK_SIZE as usize + (index - BANK_WINDOW) as usize
+    }
+
     pub fn read_word(data: DataSlice, index: Word) -> Word {
         let index = index as usize;
         let slice = &data[index..(index + WORD_SIZE as usize)];
@@ -174,3 +429,12 @@ impl Memory {
         Endian::write_u16(slice, value)
     }
 }
+
+impl fmt::Display for ProgramTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "program too large: {} bytes, at most {} bytes fit",
+               self.size,
+               self.max_size)
+    }
+}
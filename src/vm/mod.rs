@@ -1,6 +1,12 @@
 use config::*;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
-use std::io::{Read, Write};
+use std::iter::StepBy;
+use std::mem;
+use std::ops::Range;
+use std::rc::Rc;
+use std::io::{self, ErrorKind, Read, Write};
 use std::num::Wrapping;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -12,30 +18,83 @@ use utils;
 #[cfg(test)]
 pub mod tests;
 
+pub mod asm;
+pub mod backend;
+pub mod builder;
+pub mod devices;
 pub mod events;
+pub mod heap;
+pub mod history;
+pub mod host;
+pub mod input;
+pub mod journal;
 pub mod memory;
 pub mod opcodes;
 pub mod registers;
+pub mod status;
+pub mod timers;
+pub mod verifier;
 
+use self::backend::{Backend, Decoded, Program};
+use self::devices::{Device, Mapping};
+use self::devices::fs::{Filesystem, FS_SYSCALLS};
 use self::events::*;
+use self::history::{Change, History};
+use self::host::{HostContext, HostFn, Signature};
+use self::journal::{Journal, Mode, Record};
 use self::memory::*;
 use self::opcodes::*;
 use self::registers::*;
+use self::status::{ExitStatus, FaultKind};
+use self::timers::Timer;
+use self::verifier::{Diagnostic, StackUsage};
 
 pub struct VM<R: Read, W: Write> {
     input: R,
     output: W,
 
-    registers: Registers,
+    registers: RegisterFile,
     memory: Memory,
 
+    diagnostics: Box<dyn Write>,
+
     termination_scheduled: Arc<AtomicBool>,
-    terminated: bool,
+    exit_status: Option<ExitStatus>,
 
     waiting: bool,
+    read_blocked: bool,
+
+    input_buffer: Option<u8>,
+    input_eof: bool,
+    unread_input: Vec<Option<u8>>,
 
     clock: Stopwatch,
     clock_step: u8,
+
+    uptime: Stopwatch,
+    timers: [Option<Timer>; TIMERS as usize],
+
+    steps: u64,
+    instruction_pc: Word,
+    args: Data,
+
+    backend: Backend,
+    program: Option<Program>,
+    breakpoints: HashSet<Word>,
+
+    journal: Journal,
+    journal_mode: Mode,
+
+    history: Option<History>,
+
+    host_fns: HashMap<u8, HostFn>,
+    signatures: HashMap<u8, Signature>,
+
+    event_priorities: Vec<Priority>,
+
+    events_enabled: bool,
+    masked_events: Vec<bool>,
+    handler_frames: Vec<HandlerFrame>,
 }
 
 impl<R: Read, W: Write> VM<R, W> {
@@ -43,27 +102,94 @@ impl<R: Read, W: Write> VM<R, W> {
                output: W,
                executable: Data,
                termination_scheduled: Arc<AtomicBool>)
-               -> Self {
-        let memory = Memory::from_executable(executable);
+               -> Result<Self, ProgramTooLarge> {
+        let memory = Memory::from_executable(executable, HEAP_SIZE)?;
+        Ok(Self::with_memory(input, output, memory, termination_scheduled))
+    }
 
+    fn with_memory(input: R,
+                   output: W,
+                   memory: Memory,
+                   termination_scheduled: Arc<AtomicBool>)
+                   -> Self {
         VM {
-            input: input,
-            output: output,
+            input,
+            output,
 
             registers: [0; REGISTERS as usize],
-            memory: memory,
+            memory,
 
-            terminated: false,
-            termination_scheduled: termination_scheduled,
+            diagnostics: Box::new(io::stderr()),
+
+            exit_status: None,
+            termination_scheduled,
 
             waiting: false,
+            read_blocked: false,
+
+            input_buffer: None,
+            input_eof: false,
+            unread_input: vec![],
 
             clock: Stopwatch::new(),
             clock_step: 0,
+
+            uptime: Stopwatch::new(),
+            timers: [None; TIMERS as usize],
+
+            steps: 0,
+            instruction_pc: CODE_OFFSET,
+            args: vec![],
+
+            backend: Backend::Interpreter,
+            program: None,
+            breakpoints: HashSet::new(),
+
+            journal: Journal::new(),
+            journal_mode: Mode::Off,
+
+            history: None,
+
+            host_fns: HashMap::new(),
+            signatures: FS_SYSCALLS.iter().cloned().collect(),
+
+            event_priorities: vec![NORMAL_PRIORITY; EVENT_HANDLERS as usize],
+
+            events_enabled: true,
+            masked_events: vec![false; EVENT_HANDLERS as usize],
+            handler_frames: vec![],
+        }
+    }
+
+    /// Checks the code for problems which would otherwise be found only
+    /// once it's executed, see `verifier::verify`.
+    pub fn verify(&self) -> Result<(), Vec<Diagnostic>> {
+        verifier::verify(&self.memory, &self.signatures)
+    }
+
+    /// Verifies the code and returns how deep the stacks may get.
+    pub fn stack_usage(&self) -> Result<StackUsage, Vec<Diagnostic>> {
+        verifier::stack_usage(&self.memory, &self.signatures)
+    }
+
+    pub fn run(&mut self) -> ExitStatus {
+        self.start();
+
+        while self.step() {
+            if self.idle() {
+                sleep(Duration::from_millis(1));
+            }
         }
+
+        self.clock.stop();
+        self.uptime.stop();
+        self.output.flush().unwrap();
+        self.diagnostics.flush().unwrap();
+
+        self.exit_status.unwrap_or(ExitStatus::Finished)
     }
 
-    pub fn run(&mut self) {
+    pub fn start(&mut self) {
         self.set_register(PC, CODE_OFFSET as Word);
 
         self.set_register(IR, NOP as Word);
@@ -79,175 +205,489 @@ impl<R: Read, W: Write> VM<R, W> {
         self.set_register(EE, event_queue_end);
 
         self.clock.start();
+        self.uptime.start();
+    }
 
-        while !self.terminated {
-            let pc = self.get_register(PC);
-            if !self.memory.is_in_code(pc) && self.event_queue().is_empty() {
-                self.terminate();
-            } else {
-                if !self.waiting {
-                    let mut args = vec![];
-                    self.fetch()
-                        .decode(&mut args)
-                        .execute(&args);
-                    args.clear();
+    /// Executes the next instruction (unless waiting) and processes
+    /// events, returns false once the program has terminated. With
+    /// `Backend::Fused` the instructions fused with it are executed too,
+    /// up to the next breakpoint (see `set_breakpoint`).
+    pub fn step(&mut self) -> bool {
+        if self.terminated() {
+            return false;
+        }
+
+        let steps = self.steps;
+        if let Some(ref mut history) = self.history {
+            history.begin(steps);
+        }
+
+        let mut opcode = None;
+        self.read_blocked = false;
+
+        let pc = self.get_register(PC);
+        if pc == self.memory.code_end && self.next_deliverable_event().is_none() &&
+           !self.awaiting_input() {
+            self.terminate(ExitStatus::Finished);
+        } else {
+            if !self.waiting && !self.awaiting_input() && !self.memory.is_in_code(pc) {
+                // only events are left once the end of the code is reached
+                if pc != self.memory.code_end {
+                    self.terminate_with_segfault();
                 }
+            } else if !self.waiting && !self.awaiting_input() && !self.read_blocked() {
+                let mut args = mem::take(&mut self.args);
+                let instruction = self.execute_next(&mut args);
+                opcode = Some(self.get_register(IR) as u8);
+                if let Some(instruction) = instruction {
+                    self.execute_fused(instruction, &mut args);
+                }
+                args.clear();
+                self.args = args;
+            }
 
-                self.process_events()
-                    .update_clock();
+            self.process_events()
+                .update_clock()
+                .update_timers()
+                .poll_input();
+        }
 
-                sleep(Duration::from_millis(1));
+        let writes = self.memory.take_writes();
+        if let Some(ref mut history) = self.history {
+            for (index, value) in writes {
+                history.track(Change::Memory(index, value));
             }
+            history.commit(opcode);
         }
 
-        self.clock.stop();
-        self.output.flush().unwrap();
+        !self.terminated()
+    }
+
+    /// Keeps an undo log of the last `capacity` steps.
+    pub fn enable_history(&mut self, capacity: usize) {
+        self.history = Some(History::new(capacity));
+        self.memory.track_writes();
+    }
+
+    pub fn step_back(&mut self) -> bool {
+        let step = match self.history {
+            Some(ref mut history) => history.pop(),
+            None => None,
+        };
+
+        match step {
+            Some(step) => {
+                debug!("step_back to step {}", step.steps);
+                for change in step.changes.iter().rev() {
+                    match *change {
+                        Change::Register(id, value) => self.registers[id as usize] = value,
+                        Change::Memory(index, value) => self.memory.restore(index, value),
+                        Change::Waiting(value) => self.waiting = value,
+                        Change::Terminated(value) => self.exit_status = value,
+                        Change::ClockStep(value) => self.clock_step = value,
+                        Change::EventsEnabled(value) => self.events_enabled = value,
+                        Change::EventMask(event, value) => {
+                            self.masked_events[event as usize] = value
+                        }
+                        Change::FramePushed => {
+                            let _ = self.handler_frames.pop();
+                        }
+                        Change::FramePopped(frame) => self.handler_frames.push(frame),
+                        Change::Timer(id, timer) => self.timers[id as usize] = timer,
+                        Change::Alloc(ptr) => {
+                            let _ = self.memory.heap.free(ptr);
+                        }
+                        Change::Free(ptr, size) => self.memory.heap.restore(ptr, size),
+                        Change::Bank(bank) => self.memory.set_bank(bank),
+                        Change::Input(value) => {
+                            if value.is_none() {
+                                self.input_eof = false;
+                            }
+                            self.unread_input.push(value);
+                        }
+                        Change::InputBuffer(value) => self.input_buffer = value,
+                    }
+                }
+                self.steps = step.steps;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Steps back until right before the last write to `address`.
+    pub fn rewind_to_write(&mut self, address: Word) -> bool {
+        self.rewind_until(|step| step.writes_to(address))
+    }
+
+    /// Steps back until right before the last executed CALL.
+    pub fn rewind_to_call(&mut self) -> bool {
+        self.rewind_until(|step| step.opcode == Some(CALL))
+    }
+
+    fn rewind_until<F>(&mut self, found: F) -> bool
+        where F: Fn(&history::Step) -> bool
+    {
+        let position = match self.history {
+            Some(ref history) => history.position(found),
+            None => None,
+        };
+
+        match position {
+            Some(count) => {
+                for _ in 0..count {
+                    let _ = self.step_back();
+                }
+                true
+            }
+            None => false,
+        }
     }
 
     pub fn get_output_ref(&self) -> &W {
         &self.output
     }
 
-    pub fn code(&self) -> DataSlice {
+    /// Selects how instructions are decoded, see `Backend`. The code is
+    /// decoded right away for the predecoded backends.
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.program = match backend {
+            Backend::Interpreter => None,
+            Backend::Predecoded => Some(Program::new(&self.memory, false)),
+            Backend::Fused => Some(Program::new(&self.memory, true)),
+        };
+        self.backend = backend;
+    }
+
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// Makes accessing heap memory that isn't allocated (or has been freed)
+    /// raise SEGFAULT.
+    pub fn set_heap_checked(&mut self, checked: bool) {
+        self.memory.heap.set_checked(checked);
+    }
+
+    /// Where fault messages go, stderr by default.
+    pub fn set_diagnostics<D: Write + 'static>(&mut self, diagnostics: D) {
+        self.diagnostics = Box::new(diagnostics);
+    }
+
+    /// `None` while the program is still running.
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        self.exit_status
+    }
+
+    /// The TERMINATE argument (0 if the program has finished on its own),
+    /// `None` while it's running or if it has faulted.
+    pub fn exit_code(&self) -> Option<u8> {
+        self.exit_status.and_then(|status| status.code())
+    }
+
+    /// Journals the nondeterministic inputs of the run, except for reads
+    /// from devices.
+    pub fn record(&mut self) {
+        self.journal = Journal::new();
+        self.journal_mode = Mode::Record;
+    }
+
+    pub fn replay(&mut self, journal: Journal) {
+        self.journal = journal;
+        self.journal_mode = Mode::Replay;
+    }
+
+    /// Attaches a device to a range of IN/OUT ports or to a memory-mapped
+    /// range, returns false if the range is already taken.
+    pub fn attach_device(&mut self, mapping: Mapping, device: Box<dyn Device>) -> bool {
+        self.memory.attach_device(mapping, device)
+    }
+
+    /// Makes `SYSCALL id` call `host_fn`, which pops and pushes as many
+    /// bytes as `signature` says.
+    pub fn register_host_fn<F>(&mut self, id: u8, signature: Signature, host_fn: F)
+        where F: FnMut(&mut dyn HostContext) + 'static
+    {
+        let _ = self.host_fns.insert(id, Box::new(host_fn));
+        let _ = self.signatures.insert(id, signature);
+    }
+
+    /// Makes the filesystem reachable through the `FS_*` syscalls.
+    pub fn mount(&mut self, filesystem: Filesystem) {
+        let filesystem = Rc::new(RefCell::new(filesystem));
+        for &(id, signature) in FS_SYSCALLS.iter() {
+            let filesystem = filesystem.clone();
+            self.register_host_fn(id,
+                                  signature,
+                                  move |ctx| filesystem.borrow_mut().syscall(id, ctx));
+        }
+    }
+
+    /// Queued events of higher priority are delivered first, events of the
+    /// same priority in the order they were emitted. Applies to events
+    /// queued afterwards, critical events bypass the queue anyway.
+    pub fn set_event_priority(&mut self, event: u8, priority: Priority) {
+        assert!(events::is_valid(event));
+        self.event_priorities[event as usize] = priority;
+    }
+
+    pub fn journal(&self) -> &Journal {
+        &self.journal
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            pc: self.get_register(PC),
+            ir: self.get_register(IR),
+            sp: self.get_register(SP),
+            rp: self.get_register(RP),
+            ep: self.get_register(EP),
+            ee: self.get_register(EE),
+        }
+    }
+
+    pub fn flags(&self) -> Flags {
+        Flags {
+            termination_scheduled: self.termination_scheduled.load(Ordering::Relaxed),
+            terminated: self.terminated(),
+            waiting: self.waiting,
+        }
+    }
+
+    /// Moves the program to `address` (for debuggers and similar tools),
+    /// which has to be in the code or its end. Returns false if it isn't or
+    /// if the program has terminated.
+    pub fn set_pc(&mut self, address: Word) -> bool {
+        let valid = self.memory.is_in_code(address) || address == self.memory.code_end;
+        if valid && !self.terminated() {
+            self.set_register(PC, address);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Makes `Backend::Fused` stop a sequence before the instruction at
+    /// `address`, so a host calling `step` sees the PC there.
+    pub fn set_breakpoint(&mut self, address: Word) {
+        let _ = self.breakpoints.insert(address);
+    }
+
+    pub fn clear_breakpoint(&mut self, address: Word) {
+        let _ = self.breakpoints.remove(&address);
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    pub fn code(&self) -> DataSlice<'_> {
         self.memory.code()
     }
 
-    pub fn data(&self) -> DataSlice {
+    pub fn data(&self) -> DataSlice<'_> {
         self.memory.data()
     }
 
-    pub fn locals_stack(&self) -> DataSlice {
+    pub fn locals_stack(&self) -> DataSlice<'_> {
         let sp = self.get_register(SP);
         self.memory.locals_stack(sp)
     }
 
-    pub fn return_stack(&self) -> DataSlice {
+    pub fn return_stack(&self) -> DataSlice<'_> {
         let rp = self.get_register(RP);
         self.memory.return_stack(rp)
     }
 
-    pub fn event_queue(&self) -> DataSlice {
+    pub fn event_queue(&self) -> DataSlice<'_> {
         let ep = self.get_register(EP);
         let ee = self.get_register(EE);
         self.memory.event_queue(ep, ee)
     }
 
-    fn terminate(&mut self) {
-        debug!("terminate {:?}", self);
-        self.terminated = true;
+    fn terminate(&mut self, status: ExitStatus) {
+        debug!("terminate {:?} {:?}", status, self);
+        self.track(Change::Terminated(self.exit_status));
+        self.exit_status = Some(status);
+    }
+
+    fn terminated(&self) -> bool {
+        self.exit_status.is_some()
+    }
+
+    fn terminate_with_fault(&mut self, kind: FaultKind) {
+        let pc = self.instruction_pc;
+        writeln!(self.diagnostics, "{} at {}", kind, to_hex!(pc, Word)).unwrap();
+        self.terminate(ExitStatus::Fault(kind, pc));
     }
 
     fn terminate_with_segfault(&mut self) {
         self.process_event(SEGFAULT, 0x00);
     }
 
+    /// Stack overflows can't be handled, entering a handler needs room on
+    /// both stacks.
+    fn stack_overflow(&mut self) {
+        debug!("stack overflow {:?}", self);
+        if !self.terminated() {
+            self.terminate_with_fault(FaultKind::Segfault);
+        }
+    }
+
+    fn locals_stack_is_full(&self) -> bool {
+        self.get_register(SP) <= self.memory.locals_stack_begin
+    }
+
+    fn return_stack_is_full(&self) -> bool {
+        self.get_register(RP) < self.memory.return_stack_begin + WORD_SIZE
+    }
+
+    /// Executes the instructions fused with the one just executed as long
+    /// as nothing else would have happened between them: no event is
+    /// deliverable, the program hasn't terminated or started waiting, hasn't
+    /// jumped anywhere (e.g. into a fault handler) and hasn't reached a
+    /// breakpoint. History and the journal count steps, so there's no
+    /// fusion while they're on.
+    fn execute_fused(&mut self, instruction: &Instruction, args: &mut Data) {
+        if self.history.is_some() || self.journal_mode != Mode::Off {
+            return;
+        }
+
+        let mut fused = self.predecoded(self.instruction_pc).map_or(0, |decoded| decoded.fused);
+        let mut next = self.instruction_pc + instruction.size();
+        while fused > 0 && self.get_register(PC) == next && !self.breakpoints.contains(&next) &&
+              !self.terminated() && !self.waiting && !self.awaiting_input() &&
+              self.next_deliverable_event().is_none() {
+            match self.execute_next(args) {
+                Some(instruction) => next = self.instruction_pc + instruction.size(),
+                None => break,
+            }
+            fused -= 1;
+        }
+    }
+
+    /// Fetches, decodes and executes the instruction at PC, an unknown
+    /// opcode raises UNKNOWN_ERROR.
+    fn execute_next(&mut self, args: &mut Data) -> Option<&'static Instruction> {
+        args.clear();
+        let instruction = self.fetch().decode(args);
+        match instruction {
+            Some(instruction) => self.execute(instruction, args),
+            None => self.process_event(UNKNOWN_ERROR, 0x00),
+        }
+        instruction
+    }
+
+    /// Nothing to execute until an event arrives.
+    fn idle(&self) -> bool {
+        self.waiting || self.awaiting_input() || self.read_blocked ||
+        self.get_register(PC) == self.memory.code_end
+    }
+
+    /// READ of anything with nothing ready to read is held back (neither
+    /// counted as a step nor recorded) until the input is ready or ends.
+    fn read_blocked(&mut self) -> bool {
+        let pc = self.get_register(PC);
+        let size = match opcodes::instruction(self.memory.get(pc)) {
+            Some(instruction) if instruction.opcode == READ => instruction.size(),
+            _ => return false,
+        };
+        if !self.memory.is_in_code(pc + size - 1) {
+            return false;
+        }
+
+        let args = (1..size).map(|offset| self.memory.get(pc + offset)).collect::<Data>();
+        self.read_blocked = match self.extract_data_range(&args) {
+            Some((begin, end)) => begin < end && !self.input_ready() && !self.input_eof,
+            None => false,
+        };
+        self.read_blocked
+    }
+
     fn fetch(&mut self) -> &mut Self {
-        let opcode = self.next_code_byte() as Word;
-        self.set_register(IR, opcode);
+        let pc = self.get_register(PC);
+        self.instruction_pc = pc;
+        let opcode = match self.predecoded(pc) {
+            Some(decoded) => {
+                self.increment_register(PC);
+                decoded.instruction.opcode
+            }
+            None => self.next_code_byte(),
+        };
+        self.set_register(IR, opcode as Word);
+        self.steps += 1;
         self
     }
 
-    fn decode(&mut self, args: &mut Data) -> &mut Self {
-        let opcode = self.get_register(IR) as u8;
-        match opcode {
-            ADD | SUB | MUL | DIV | MOD | SWP | AND | OR | XOR => {
-                if self.locals_stack().len() >= 2 {
-                    args.push(self.locals_stack_pop());
-                    args.push(self.locals_stack_pop());
-                }
+    /// Collects the operands and the items the instruction takes, leaves
+    /// `args` empty if there are too few of them. Returns `None` for unknown
+    /// opcodes.
+    fn decode(&mut self, args: &mut Data) -> Option<&'static Instruction> {
+        let instruction = match self.predecoded(self.instruction_pc) {
+            Some(decoded) => {
+                args.extend_from_slice(decoded.operands());
+                self.increment_register_by(PC, decoded.instruction.operands_size());
+                decoded.instruction
             }
-            INC | DEC | NOT => {
-                if !self.locals_stack().is_empty() {
-                    args.push(self.locals_stack_pop());
-                }
-            }
-            SHL | SHR => {
-                if !self.locals_stack().is_empty() {
-                    args.push(self.locals_stack_pop());
+            None => {
+                let opcode = self.get_register(IR) as u8;
+                let instruction = opcodes::instruction(opcode)?;
+
+                for _ in 0..instruction.operands_size() {
                     args.push(self.next_code_byte());
                 }
-            }
-            PUSH => args.push(self.next_code_byte()),
-            POP | NOP | WAIT => (),
-            LOAD => {
-                args.push(self.next_code_byte());
-                args.push(self.next_code_byte());
-            }
-            LOAD_OFFS => {
-                if !self.locals_stack().is_empty() {
-                    args.push(self.next_code_byte());
-                    args.push(self.next_code_byte());
 
-                    let offset = self.locals_stack_top();
-                    args.push(offset);
-                }
+                instruction
             }
-            STORE => {
-                if !self.locals_stack().is_empty() {
-                    args.push(self.next_code_byte());
-                    args.push(self.next_code_byte());
+        };
+        let opcode = instruction.opcode;
 
-                    let data = self.locals_stack_top();
-                    args.push(data);
-                }
-            }
-            STORE_OFFS => {
-                if self.locals_stack().len() >= 2 {
-                    args.push(self.next_code_byte());
-                    args.push(self.next_code_byte());
+        let takes = instruction.takes as usize;
+        if self.locals_stack().len() < takes {
+            args.clear();
+            return Some(instruction);
+        }
 
-                    let data = self.locals_stack()[1];
-                    let offset = self.locals_stack()[0];
-                    args.push(data);
-                    args.push(offset);
-                }
-            }
-            RET => {
-                if self.return_stack().len() >= 2 {
-                    args.push(self.return_stack()[0]);
-                    args.push(self.return_stack()[1]);
-                    let _ = self.return_stack_pop();
-                }
-            }
-            JMP | CALL => {
-                args.push(self.next_code_byte());
-                args.push(self.next_code_byte());
-            }
-            JE | JNE | JL | JG | JLE | JGE => {
-                if self.locals_stack().len() >= 2 {
-                    args.push(self.locals_stack()[0]);
-                    args.push(self.locals_stack()[1]);
-                }
-                args.push(self.next_code_byte());
-                args.push(self.next_code_byte());
-            }
+        for _ in 0..instruction.pops {
+            args.push(self.locals_stack_pop());
+        }
+        let peeks = takes - instruction.pops as usize;
+        args.extend_from_slice(&self.locals_stack()[..peeks]);
+
+        match opcode {
             EMIT => {
-                let event = self.next_code_byte();
                 let argument = if self.locals_stack().is_empty() {
                     0x00
                 } else {
                     self.locals_stack_top()
                 };
-                args.push(event);
                 args.push(argument);
             }
-            SUBSCRIBE => {
-                args.push(self.next_code_byte());
-                args.push(self.next_code_byte());
-                args.push(self.next_code_byte());
+            RET | RETI => {
+                if self.return_stack().len() >= 2 {
+                    args.push(self.return_stack()[0]);
+                    args.push(self.return_stack()[1]);
+                    let _ = self.return_stack_pop();
+                } else {
+                    args.clear();
+                }
             }
-            UNSUBSCRIBE => args.push(self.next_code_byte()),
-            _ => unimplemented!(),
+            _ => (),
         }
 
-        self
+        Some(instruction)
     }
 
-    fn execute(&mut self, args: DataSlice) {
+    fn execute(&mut self, instruction: &Instruction, args: DataSlice) {
         debug!("execute {:?}", self);
 
-        let opcode = self.get_register(IR) as u8;
-        let need_args = ![NOP, POP, WAIT].contains(&opcode);
+        let opcode = instruction.opcode;
+        let need_args = instruction.operands_size() > 0 || instruction.takes > 0 ||
+                        opcode == RET || opcode == RETI;
 
         if need_args && args.is_empty() {
             self.terminate_with_segfault();
@@ -279,8 +719,8 @@ impl<R: Read, W: Write> VM<R, W> {
                     let value = Wrapping(args[0]) - Wrapping(1);
                     self.locals_stack_push(value.0);
                 }
-                SHL => self.apply_bin_operator(args, |x, y| x << y.0 as usize),
-                SHR => self.apply_bin_operator(args, |x, y| x >> y.0 as usize),
+                SHL => self.apply_bin_operator(&[args[1], args[0]], |x, y| x << y.0 as usize),
+                SHR => self.apply_bin_operator(&[args[1], args[0]], |x, y| x >> y.0 as usize),
                 XOR => self.apply_bin_operator(args, |x, y| x ^ y),
                 AND => self.apply_bin_operator(args, |x, y| x & y),
                 OR => self.apply_bin_operator(args, |x, y| x | y),
@@ -295,13 +735,7 @@ impl<R: Read, W: Write> VM<R, W> {
                         self.locals_stack_push(args[0]);
                     }
                 }
-                POP => {
-                    if self.locals_stack().is_empty() {
-                        self.terminate_with_segfault();
-                    } else {
-                        let _ = self.locals_stack_pop();
-                    }
-                }
+                POP => (),
                 SWP => {
                     self.locals_stack_push(args[0]);
                     self.locals_stack_push(args[1]);
@@ -333,13 +767,60 @@ impl<R: Read, W: Write> VM<R, W> {
                     }
                 }
                 STORE_OFFS => {
-                    let data = args[2];
-                    let offset = args[3];
+                    let offset = args[2];
+                    let data = args[3];
                     match self.extract_data_ptr(args, offset) {
                         Some(ptr) => self.memory.put(ptr, data),
                         None => self.terminate_with_segfault(),
                     }
                 }
+                LOAD_PTR => {
+                    let offset = args[0];
+                    let data = self.extract_heap_ptr(&args[1..], offset)
+                        .map(|ptr| self.memory.get(ptr));
+                    match data {
+                        Some(data) => self.locals_stack_push(data),
+                        None => self.terminate_with_segfault(),
+                    }
+                }
+                STORE_PTR => {
+                    let offset = args[0];
+                    let data = args[1];
+                    match self.extract_heap_ptr(&args[2..], offset) {
+                        Some(ptr) => self.memory.put(ptr, data),
+                        None => self.terminate_with_segfault(),
+                    }
+                }
+                ALLOC => {
+                    let size = Memory::read_word(args, 0);
+                    let ptr = self.memory.heap.alloc(size);
+                    if let Some(ptr) = ptr {
+                        self.track(Change::Alloc(ptr));
+                        for index in ptr..(ptr + size) {
+                            self.memory.put(index, 0x00);
+                        }
+                    }
+
+                    let ptr = ptr.unwrap_or(0x0000);
+                    self.locals_stack_push((ptr >> 8) as u8);
+                    self.locals_stack_push(ptr as u8);
+                }
+                FREE => {
+                    let ptr = Memory::read_word(args, 0);
+                    match self.memory.heap.free(ptr) {
+                        Some(size) => self.track(Change::Free(ptr, size)),
+                        None => self.terminate_with_segfault(),
+                    }
+                }
+                BANK => {
+                    let bank = args[0];
+                    if bank < BANKS {
+                        self.track(Change::Bank(self.memory.bank()));
+                        self.memory.set_bank(bank);
+                    } else {
+                        self.terminate_with_segfault();
+                    }
+                }
                 JMP => self.jump(args),
                 JE => self.jump_if(args, |x, y| x == y),
                 JNE => self.jump_if(args, |x, y| x != y),
@@ -356,38 +837,159 @@ impl<R: Read, W: Write> VM<R, W> {
                         self.jump(args);
                     }
                 }
-                RET => self.jump(args),
+                RET => {
+                    self.jump(args);
+                    let _ = self.leave_handler(false);
+                }
+                RETI => {
+                    if self.leave_handler(true) {
+                        self.jump(args);
+                    } else {
+                        self.terminate_with_segfault();
+                    }
+                }
+                SYSCALL => {
+                    let id = args[0];
+                    match self.host_fns.remove(&id) {
+                        Some(mut host_fn) => {
+                            host_fn(self);
+                            let _ = self.host_fns.insert(id, host_fn);
+                        }
+                        None => self.process_event(UNKNOWN_ERROR, 0x00),
+                    }
+                }
                 EMIT => {
                     // compiler won't let args be empty
                     let event = args[0];
                     let argument = args[1];
-                    if events::is_critical(event) {
-                        self.process_event(event, argument);
-                    } else {
-                        self.event_queue_push(event, argument);
-                    }
+                    self.emit(event, argument);
                 }
-                WAIT => self.waiting = true,
+                WAIT => self.set_waiting(true),
                 SUBSCRIBE => {
                     let event = args[0];
-                    let handler_address = Memory::read_word(&args, 1);
-                    self.memory.set_event_handler(event, handler_address);
+                    let handler_address = Memory::read_word(args, 1);
+                    if events::is_valid(event) && self.memory.is_in_code(handler_address) {
+                        self.memory.set_event_handler(event, handler_address);
+                    } else {
+                        self.terminate_with_segfault();
+                    }
                 }
                 UNSUBSCRIBE => {
                     let event = args[0];
-                    self.memory.set_event_handler(event, 0x0000);
+                    if events::is_valid(event) {
+                        self.memory.set_event_handler(event, 0x0000);
+                    } else {
+                        self.terminate_with_segfault();
+                    }
+                }
+                MASK | UNMASK => {
+                    let event = args[0];
+                    if events::is_valid(event) {
+                        self.set_event_masked(event, opcode == MASK);
+                    } else {
+                        self.terminate_with_segfault();
+                    }
+                }
+                TIMER_SET | TIMER_ONCE => {
+                    let id = args[0];
+                    let period = Memory::read_word(args, 1);
+                    if id < TIMERS && period > 0 {
+                        let now = self.uptime.elapsed_ms();
+                        let timer = Timer::new(period, opcode == TIMER_SET, now);
+                        self.set_timer(id, Some(timer));
+                    } else {
+                        self.terminate_with_segfault();
+                    }
+                }
+                TIMER_STOP => {
+                    let id = args[0];
+                    if id < TIMERS {
+                        self.set_timer(id, None);
+                    } else {
+                        self.terminate_with_segfault();
+                    }
+                }
+                MASK_ALL => self.set_events_enabled(false),
+                UNMASK_ALL => self.set_events_enabled(true),
+                IN => {
+                    let port = args[0];
+                    match self.memory.read_port(port) {
+                        Some(value) => self.locals_stack_push(value),
+                        None => self.terminate_with_segfault(),
+                    }
+                }
+                OUT => {
+                    let port = args[0];
+                    let value = args[1];
+                    if !self.memory.write_port(port, value) {
+                        self.terminate_with_segfault();
+                    }
+                }
+                WRITE => {
+                    match self.extract_data_range(args) {
+                        Some((begin, end)) => {
+                            let data = &self.memory.raw[begin as usize..end as usize];
+                            self.output.write_all(data).unwrap();
+                            if data.contains(&b'\n') {
+                                self.output.flush().unwrap();
+                            }
+                        }
+                        None => self.terminate_with_segfault(),
+                    }
+                }
+                READ => {
+                    let range = self.extract_data_range(args);
+                    let stack_full = self.get_register(SP) <= self.memory.locals_stack_begin;
+                    match range {
+                        Some((begin, end)) if begin < end && !self.input_ready() &&
+                                              !self.input_eof => {
+                            // retry once there's something to read, see `read_blocked`
+                            self.decrement_register_by(PC, instruction.size());
+                        }
+                        Some((begin, end)) if !stack_full => {
+                            let mut ptr = begin;
+                            while ptr < end && self.input_ready() {
+                                let value = self.take_input().unwrap();
+                                self.memory.put(ptr, value);
+                                ptr += 1;
+                            }
+                            self.locals_stack_push((ptr - begin) as u8);
+                        }
+                        _ => self.terminate_with_segfault(),
+                    }
                 }
-                _ => unimplemented!(),
+                _ => self.process_event(UNKNOWN_ERROR, 0x00),
             }
         }
     }
 
     fn extract_data_ptr(&self, args: DataSlice, offset: u8) -> Option<Word> {
-        let ptr = Memory::read_word(&args, 0) + offset as Word;
-        if self.memory.is_in_data(ptr) {
-            Some(ptr)
-        } else {
-            None
+        let ptr = Memory::read_word(args, 0).checked_add(offset as Word);
+        match ptr {
+            Some(ptr) if self.memory.is_accessible(ptr) => Some(ptr),
+            _ => None,
+        }
+    }
+
+    /// Pointers are words on the locals stack, low byte on top.
+    fn extract_heap_ptr(&self, args: DataSlice, offset: u8) -> Option<Word> {
+        let ptr = Memory::read_word(args, 0).checked_add(offset as Word);
+        match ptr {
+            Some(ptr) if self.memory.is_in_heap(ptr) || self.memory.is_in_data(ptr) => Some(ptr),
+            _ => None,
+        }
+    }
+
+    /// Block transfers take an address and a length.
+    fn extract_data_range(&self, args: DataSlice) -> Option<(Word, Word)> {
+        let begin = Memory::read_word(args, 0);
+        let len = args[2] as Word;
+        match begin.checked_add(len) {
+            Some(end) if len == 0 => Some((begin, end)),
+            Some(end) if self.memory.is_in_data(begin) && self.memory.is_in_data(end - 1) => {
+                Some((begin, end))
+            }
+            _ => None,
         }
     }
 
@@ -401,17 +1003,13 @@ impl<R: Read, W: Write> VM<R, W> {
     fn jump_if<F>(&mut self, args: DataSlice, condition: F)
         where F: Fn(u8, u8) -> bool
     {
-        if args.len() < 4 {
-            self.terminate_with_segfault();
-        } else {
-            if condition(args[0], args[1]) {
-                self.jump(&args[2..])
-            }
+        if condition(args[2], args[3]) {
+            self.jump(args)
         }
     }
 
     fn jump(&mut self, args: DataSlice) {
-        let new_pc = Memory::read_word(&args, 0);
+        let new_pc = Memory::read_word(args, 0);
         self.set_register(PC, new_pc);
     }
 
@@ -425,30 +1023,41 @@ impl<R: Read, W: Write> VM<R, W> {
             debug!("handler is NOT set");
             match event {
                 INPUT => {
-                    let mut buffer = [0; 1];
-                    let _ = self.input.read(&mut buffer).unwrap();
-                    self.locals_stack_push(buffer[0]);
+                    // delivered only once the input is ready
+                    let value = self.take_input().unwrap_or(0x00);
+                    self.locals_stack_push(value);
                 }
                 OUTPUT => {
-                    self.output.write(&[argument]).unwrap();
+                    self.output.write_all(&[argument]).unwrap();
                     if argument == b'\n' {
                         self.output.flush().unwrap();
                     }
                 }
-                SEGFAULT => {
-                    self.output.write(b"Segfault").unwrap();
-                }
-                UNKNOWN_ERROR => {
-                    self.output.write(b"Unknown Error").unwrap();
-                }
+                TERMINATE => self.terminate(ExitStatus::Terminated(argument)),
+                SEGFAULT => self.terminate_with_fault(FaultKind::Segfault),
+                UNKNOWN_ERROR => self.terminate_with_fault(FaultKind::UnknownError),
                 _ => debug!("no default handler"),
             }
-
-            if events::is_critical(event) {
-                self.terminate();
-            }
         } else {
             debug!("handler is set");
+            if self.locals_stack_is_full() || self.return_stack_is_full() {
+                self.stack_overflow();
+                return;
+            }
+
+            let frame = HandlerFrame {
+                rp: self.get_register(RP),
+                sp: self.get_register(SP),
+                waiting: self.waiting,
+                events_enabled: self.events_enabled,
+            };
+            self.track(Change::FramePushed);
+            self.handler_frames.push(frame);
+            self.set_events_enabled(false);
+            if self.waiting {
+                self.set_waiting(false);
+            }
+
             let pc = self.get_register(PC);
             self.return_stack_push(pc);
 
@@ -457,26 +1066,210 @@ impl<R: Read, W: Write> VM<R, W> {
         }
     }
 
+    /// Restores the state saved on handler entry if the return address
+    /// has just been popped off the handler's frame. Restoring the stack
+    /// drops the argument and whatever the handler has left on top of it.
+    fn leave_handler(&mut self, restore_stack: bool) -> bool {
+        let rp = self.get_register(RP);
+        match self.handler_frames.last().cloned() {
+            Some(frame) if frame.rp == rp => {
+                let _ = self.handler_frames.pop();
+                self.track(Change::FramePopped(frame));
+                self.set_events_enabled(frame.events_enabled);
+                if self.waiting != frame.waiting {
+                    self.set_waiting(frame.waiting);
+                }
+                if restore_stack {
+                    self.set_register(SP, frame.sp);
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
     fn process_events(&mut self) -> &mut Self {
-        let nothing_to_process = self.terminated || self.event_queue().is_empty();
+        if self.event_queued(INPUT) {
+            let _ = self.input_ready();
+        }
 
-        if !nothing_to_process {
+        let next_event = if self.terminated() {
+            None
+        } else {
+            self.next_deliverable_event()
+        };
+
+        if let Some(index) = next_event {
             if self.waiting {
-                self.waiting = false;
+                self.set_waiting(false);
             }
 
-            let (event, argument) = self.event_queue_pop();
+            let (event, argument) = self.event_queue_remove(index);
             self.process_event(event, argument);
         }
 
-        if self.termination_scheduled.load(Ordering::Relaxed) {
-            self.process_event(TERMINATE, 0x00);
+        if self.termination_requested() {
+            if self.memory.get_event_handler(TERMINATE) == 0x0000 {
+                self.terminate(ExitStatus::Interrupted);
+            } else {
+                self.process_event(TERMINATE, INTERRUPTED);
+            }
         }
 
         self
     }
 
+    /// Takes the request, so a single Ctrl-C is delivered and recorded only
+    /// once.
+    fn termination_requested(&mut self) -> bool {
+        let requested = self.termination_scheduled.swap(false, Ordering::Relaxed);
+        match self.journal_mode {
+            Mode::Off => requested,
+            Mode::Record => {
+                if requested {
+                    self.journal.push(self.steps, Record::Terminate);
+                }
+                requested
+            }
+            Mode::Replay => requested || self.journal.next_termination(self.steps),
+        }
+    }
+
+    /// Reads the next input byte into the buffer unless it's there already,
+    /// returns false if the input has nothing to read (yet).
+    fn input_ready(&mut self) -> bool {
+        if self.input_buffer.is_some() {
+            return true;
+        } else if self.input_eof {
+            return false;
+        }
+
+        // input taken back by `step_back` is read again first
+        if let Some(value) = self.unread_input.pop() {
+            match value {
+                Some(value) => self.receive_input(value),
+                None => self.reach_input_eof(),
+            }
+            return self.input_buffer.is_some();
+        }
+
+        if self.journal_mode == Mode::Replay {
+            if let Some(value) = self.journal.next_input(self.steps) {
+                self.receive_input(value);
+            } else if self.journal.next_eof(self.steps) {
+                self.reach_input_eof();
+            }
+            // without an Eof record the input hadn't ended, it's just not
+            // ready (yet)
+            return self.input_buffer.is_some();
+        }
+
+        let mut buffer = [0; 1];
+        match self.input.read(&mut buffer) {
+            Ok(0) => {
+                if self.journal_mode == Mode::Record {
+                    self.journal.push(self.steps, Record::Eof);
+                }
+                self.reach_input_eof();
+            }
+            Ok(_) => {
+                if self.journal_mode == Mode::Record {
+                    self.journal.push(self.steps, Record::Input(buffer[0]));
+                }
+                self.receive_input(buffer[0]);
+            }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock ||
+                          e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => {
+                error!("input error: {}", e);
+                self.reach_input_eof();
+            }
+        }
+
+        self.input_buffer.is_some()
+    }
+
+    fn receive_input(&mut self, value: u8) {
+        self.track(Change::Input(Some(value)));
+        self.set_input_buffer(Some(value));
+    }
+
+    fn take_input(&mut self) -> Option<u8> {
+        let value = self.input_buffer;
+        if value.is_some() {
+            self.set_input_buffer(None);
+        }
+        value
+    }
+
+    fn set_input_buffer(&mut self, value: Option<u8>) {
+        let input_buffer = self.input_buffer;
+        self.track(Change::InputBuffer(input_buffer));
+        self.input_buffer = value;
+    }
+
+    fn reach_input_eof(&mut self) {
+        debug!("input eof");
+        self.track(Change::Input(None));
+        self.input_eof = true;
+        if self.memory.get_event_handler(INPUT_EOF) != 0x0000 {
+            self.event_queue_push(INPUT_EOF, 0x00);
+        }
+    }
+
+    /// Emits INPUT with the next byte as soon as it's available if the
+    /// program has subscribed to INPUT.
+    fn poll_input(&mut self) {
+        let subscribed = self.memory.get_event_handler(INPUT) != 0x0000;
+        if subscribed && !self.terminated() && !self.event_queued(INPUT) && self.input_ready() {
+            let value = self.take_input().unwrap();
+            self.event_queue_push(INPUT, value);
+        }
+    }
+
+    /// EMIT INPUT without a handler stalls the program until the input is
+    /// ready, while other events are still delivered.
+    fn awaiting_input(&self) -> bool {
+        let ready = self.input_buffer.is_some() || self.input_eof;
+        !ready && self.queued_events().any(|index| self.input_expected_at(index))
+    }
+
+    /// EMIT INPUT without a handler queues the number of handlers running
+    /// at that moment, the byte has to land on the stack it was requested
+    /// from rather than on the stack of a handler that has interrupted it.
+    fn input_expected_at(&self, index: Word) -> bool {
+        self.memory.get(index) == INPUT && self.memory.get_event_handler(INPUT) == 0x0000 &&
+        self.memory.get(index + 1) == self.handler_frames.len() as u8
+    }
+
+    fn event_queued(&self, event: u8) -> bool {
+        self.queued_events().any(|index| self.memory.get(index) == event)
+    }
+
+    fn queued_events(&self) -> StepBy<Range<Word>> {
+        let ep = self.get_register(EP);
+        let ee = self.get_register(EE);
+        (ep..ee).step_by(EVENT_SIZE as usize)
+    }
+
     fn event_queue_push(&mut self, event: u8, argument: u8) {
+        let event_queue_begin = self.memory.event_queue_begin;
+        if self.get_register(EP) < event_queue_begin + EVENT_SIZE {
+            self.event_queue_compact();
+        }
+
+        if self.get_register(EP) < event_queue_begin + EVENT_SIZE {
+            // the newest entry of the lowest priority is at EP
+            let lowest = self.memory.get(self.get_register(EP));
+            if self.event_priority(event) <= self.event_priority(lowest) {
+                warn!("event queue is full, dropping event={}", to_hex!(event));
+                return;
+            }
+
+            warn!("event queue is full, dropping event={}", to_hex!(lowest));
+            self.increment_register_by(EP, EVENT_SIZE);
+        }
+
         self.decrement_register(EP);
         let ep = self.get_register(EP);
         self.memory.put(ep, argument);
@@ -484,6 +1277,87 @@ impl<R: Read, W: Write> VM<R, W> {
         self.decrement_register(EP);
         let ep = self.get_register(EP);
         self.memory.put(ep, event);
+
+        // keep the queue ordered by priority, preserving FIFO within one
+        let ee = self.get_register(EE);
+        let mut index = ep;
+        while index + EVENT_SIZE < ee {
+            let next = index + EVENT_SIZE;
+            let next_event = self.memory.get(next);
+            if self.event_priority(next_event) >= self.event_priority(event) {
+                break;
+            }
+
+            let next_argument = self.memory.get(next + 1);
+            self.memory.put(index, next_event);
+            self.memory.put(index + 1, next_argument);
+            self.memory.put(next, event);
+            self.memory.put(next + 1, argument);
+            index = next;
+        }
+    }
+
+    fn event_queue_compact(&mut self) {
+        let ep = self.get_register(EP);
+        let ee = self.get_register(EE);
+        let shift = self.memory.event_queue_end - ee;
+
+        if shift > 0 {
+            for index in (ep..ee).rev() {
+                let value = self.memory.get(index);
+                self.memory.put(index + shift, value);
+            }
+
+            self.increment_register_by(EP, shift);
+            self.increment_register_by(EE, shift);
+        }
+    }
+
+    /// Masking defers only the events that would interrupt the program with
+    /// a handler, events handled by the VM itself are always delivered
+    /// (INPUT once the input is ready).
+    fn next_deliverable_event(&self) -> Option<Word> {
+        let ep = self.get_register(EP);
+        let mut index = self.get_register(EE);
+
+        while index > ep {
+            index -= EVENT_SIZE;
+            let event = self.memory.get(index);
+            let handled = self.memory.get_event_handler(event) != 0x0000;
+            let masked = !self.events_enabled || self.masked_events[event as usize];
+            let ready = self.input_buffer.is_some() || self.input_eof;
+            let pending_input = event == INPUT && !handled &&
+                                (!ready || !self.input_expected_at(index));
+            if (!masked || !handled) && !pending_input {
+                return Some(index);
+            }
+        }
+
+        None
+    }
+
+    fn event_queue_remove(&mut self, index: Word) -> (u8, u8) {
+        if index + EVENT_SIZE == self.get_register(EE) {
+            return self.event_queue_pop();
+        }
+
+        let ep = self.get_register(EP);
+        assert_ge!(index, ep);
+
+        let event = self.memory.get(index);
+        let argument = self.memory.get(index + 1);
+
+        for i in (ep..index).rev() {
+            let value = self.memory.get(i);
+            self.memory.put(i + EVENT_SIZE, value);
+        }
+        self.increment_register_by(EP, EVENT_SIZE);
+
+        (event, argument)
+    }
+
+    fn event_priority(&self, event: u8) -> Priority {
+        self.event_priorities[event as usize]
     }
 
     fn event_queue_pop(&mut self) -> (u8, u8) {
@@ -508,16 +1382,65 @@ impl<R: Read, W: Write> VM<R, W> {
         (event, argument)
     }
 
-    fn update_clock(&mut self) {
-        if self.clock.elapsed_ms() > CLOCK_TIMEOUT_MS {
+    fn update_clock(&mut self) -> &mut Self {
+        let ticked = match self.journal_mode {
+            Mode::Replay => self.journal.next_clock(self.steps),
+            _ => self.clock.elapsed_ms() > CLOCK_TIMEOUT_MS,
+        };
+
+        if ticked {
             self.clock.restart();
 
+            if self.journal_mode == Mode::Record {
+                self.journal.push(self.steps, Record::Clock);
+            }
+
             let clock_step = self.clock_step;
             self.event_queue_push(CLOCK, clock_step);
 
             let new_clock_step = Wrapping(clock_step) + Wrapping(1);
+            self.track(Change::ClockStep(clock_step));
             self.clock_step = new_clock_step.0;
         }
+
+        self
+    }
+
+    fn update_timers(&mut self) -> &mut Self {
+        if self.journal_mode == Mode::Replay {
+            while let Some(id) = self.journal.next_timer(self.steps) {
+                self.fire_timer(id);
+            }
+            return self;
+        }
+
+        let now = self.uptime.elapsed_ms();
+        for id in 0..TIMERS {
+            let due = self.timers[id as usize].is_some_and(|timer| timer.is_due(now));
+            if due {
+                if self.journal_mode == Mode::Record {
+                    self.journal.push(self.steps, Record::Timer(id));
+                }
+                self.fire_timer(id);
+            }
+        }
+
+        self
+    }
+
+    fn fire_timer(&mut self, id: u8) {
+        match self.timers.get(id as usize).cloned() {
+            Some(Some(timer)) => {
+                let now = self.uptime.elapsed_ms();
+                self.set_timer(id, timer.fired(now));
+                self.event_queue_push(TIMER + id, timer.ticks);
+            }
+            _ => warn!("timer {} is not armed", id),
+        }
+    }
+
+    fn predecoded(&self, address: Word) -> Option<Decoded> {
+        self.program.as_ref().and_then(|program| program.get(address))
     }
 
     fn next_code_byte(&mut self) -> u8 {
@@ -527,28 +1450,57 @@ impl<R: Read, W: Write> VM<R, W> {
         value
     }
 
-    fn get_register(&self, id: u8) -> Word {
+    fn get_register(&self, id: Register) -> Word {
         self.registers[id as usize]
     }
 
-    fn set_register(&mut self, id: u8, value: Word) {
-        debug!("set r{:x} = {}", id, to_hex!(value, Word));
+    fn set_register(&mut self, id: Register, value: Word) {
+        debug!("set {:?} = {}", id, to_hex!(value, Word));
+        self.track(Change::Register(id, self.registers[id as usize]));
         self.registers[id as usize] = value;
     }
 
-    fn increment_register(&mut self, id: u8) {
+    fn set_waiting(&mut self, waiting: bool) {
+        self.track(Change::Waiting(self.waiting));
+        self.waiting = waiting;
+    }
+
+    fn set_timer(&mut self, id: u8, timer: Option<Timer>) {
+        self.track(Change::Timer(id, self.timers[id as usize]));
+        self.timers[id as usize] = timer;
+    }
+
+    fn set_events_enabled(&mut self, enabled: bool) {
+        self.track(Change::EventsEnabled(self.events_enabled));
+        self.events_enabled = enabled;
+    }
+
+    fn set_event_masked(&mut self, event: u8, masked: bool) {
+        self.track(Change::EventMask(event, self.masked_events[event as usize]));
+        self.masked_events[event as usize] = masked;
+    }
+
+    fn track(&mut self, change: Change) {
+        if let Some(ref mut history) = self.history {
+            history.track(change);
+        }
+    }
+
+    fn increment_register(&mut self, id: Register) {
         self.increment_register_by(id, 1);
     }
 
-    fn decrement_register(&mut self, id: u8) {
+    fn decrement_register(&mut self, id: Register) {
         self.decrement_register_by(id, 1);
     }
 
-    fn increment_register_by(&mut self, id: u8, acc: Word) {
+    fn increment_register_by(&mut self, id: Register, acc: Word) {
+        self.track(Change::Register(id, self.registers[id as usize]));
         self.registers[id as usize] += acc;
     }
 
-    fn decrement_register_by(&mut self, id: u8, acc: Word) {
+    fn decrement_register_by(&mut self, id: Register, acc: Word) {
+        self.track(Change::Register(id, self.registers[id as usize]));
         self.registers[id as usize] -= acc;
     }
 
@@ -556,6 +1508,11 @@ impl<R: Read, W: Write> VM<R, W> {
         debug!("locals_stack_push {} to [{}]",
                to_hex!(value),
                utils::data_to_hex(self.locals_stack()));
+        if self.locals_stack_is_full() {
+            self.stack_overflow();
+            return;
+        }
+
         self.decrement_register(SP);
         let sp = self.get_register(SP);
         self.memory.put(sp, value);
@@ -579,6 +1536,10 @@ impl<R: Read, W: Write> VM<R, W> {
         debug!("return_stack_push {} to [{}]",
                to_hex!(address, Word),
                utils::data_to_hex(self.return_stack()));
+        if self.return_stack_is_full() {
+            self.stack_overflow();
+            return;
+        }
 
         self.decrement_register_by(RP, WORD_SIZE);
         let rp = self.get_register(RP);
@@ -601,6 +1562,59 @@ impl<R: Read, W: Write> VM<R, W> {
     }
 }
 
+impl<R: Read, W: Write> HostContext for VM<R, W> {
+    fn pop(&mut self) -> Option<u8> {
+        if self.locals_stack().is_empty() {
+            None
+        } else {
+            Some(self.locals_stack_pop())
+        }
+    }
+
+    fn push(&mut self, value: u8) -> bool {
+        if self.locals_stack_is_full() {
+            false
+        } else {
+            self.locals_stack_push(value);
+            true
+        }
+    }
+
+    fn load(&self, address: Word) -> Option<u8> {
+        if self.memory.is_in_data(address) {
+            Some(self.memory.get(address))
+        } else {
+            None
+        }
+    }
+
+    fn store(&mut self, address: Word, value: u8) -> bool {
+        if self.memory.is_in_data(address) {
+            self.memory.put(address, value);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn emit(&mut self, event: u8, argument: u8) {
+        if !events::is_valid(event) {
+            self.terminate_with_segfault();
+        } else if events::is_critical(event) {
+            self.process_event(event, argument);
+        } else if event == INPUT && self.memory.get_event_handler(INPUT) == 0x0000 {
+            let depth = self.handler_frames.len() as u8;
+            self.event_queue_push(INPUT, depth);
+        } else {
+            self.event_queue_push(event, argument);
+        }
+    }
+
+    fn fault(&mut self) {
+        self.terminate_with_segfault();
+    }
+}
+
 impl<R: Read, W: Write> fmt::Debug for VM<R, W> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f,
@@ -1,3 +1,5 @@
+use config::*;
+
 pub const NOP: u8 = 0x00;
 
 pub const ADD: u8 = 0x01;
@@ -22,6 +24,8 @@ pub const STORE: u8 = 0x23;       // stack -> data
 pub const STORE_OFFS: u8 = 0x24;  // stack -> data + offset
 pub const LOAD: u8 = 0x25;        // data -> stack
 pub const LOAD_OFFS: u8 = 0x26;   // data + offset -> stack
+pub const LOAD_PTR: u8 = 0x27;    // [pointer + offset] -> stack
+pub const STORE_PTR: u8 = 0x28;   // stack -> [pointer + offset]
 
 pub const JMP: u8 = 0x30;
 pub const JE: u8 = 0x31;          // ==
@@ -33,8 +37,163 @@ pub const JGE: u8 = 0x36;         // >=
 
 pub const CALL: u8 = 0x41;
 pub const RET: u8 = 0x42;
+pub const SYSCALL: u8 = 0x43;     // call a host function
+pub const RETI: u8 = 0x44;        // return from event handler
 
 pub const EMIT: u8 = 0x50;
 pub const WAIT: u8 = 0x51;
 pub const SUBSCRIBE: u8 = 0x52;
 pub const UNSUBSCRIBE: u8 = 0x53;
+pub const MASK: u8 = 0x54;
+pub const UNMASK: u8 = 0x55;
+pub const MASK_ALL: u8 = 0x56;
+pub const UNMASK_ALL: u8 = 0x57;
+pub const TIMER_SET: u8 = 0x58;   // periodic
+pub const TIMER_ONCE: u8 = 0x59;  // one-shot
+pub const TIMER_STOP: u8 = 0x5a;
+
+pub const IN: u8 = 0x60;          // port -> stack
+pub const OUT: u8 = 0x61;         // stack -> port
+pub const WRITE: u8 = 0x62;       // data -> output
+pub const READ: u8 = 0x63;        // input -> data
+
+pub const ALLOC: u8 = 0x70;       // size -> pointer
+pub const FREE: u8 = 0x71;        // pointer
+pub const BANK: u8 = 0x72;        // select the bank mapped into the window
+
+/// What follows the opcode in the code.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operand {
+    /// Immediate value, shift, port, host function or timer id.
+    Byte,
+    Event,
+    /// Jump or call target, may be the end of the code.
+    Code,
+    /// Event handler address.
+    Handler,
+    /// Address of the data the instruction accesses.
+    Data,
+    /// Immediate word, e.g. a timer period.
+    Word,
+}
+
+impl Operand {
+    pub fn size(&self) -> Word {
+        match *self {
+            Operand::Byte | Operand::Event => 1,
+            Operand::Code | Operand::Handler | Operand::Data | Operand::Word => WORD_SIZE,
+        }
+    }
+}
+
+/// Encoding and stack effect of an instruction: it needs `takes` items on
+/// the locals stack, pops `pops` of them and pushes `pushes` items. The
+/// interpreter passes it the operands followed by the items it takes, top
+/// first.
+#[derive(Debug, PartialEq)]
+pub struct Instruction {
+    pub opcode: u8,
+    pub mnemonic: &'static str,
+    pub operands: &'static [Operand],
+    pub takes: u8,
+    pub pops: u8,
+    pub pushes: u8,
+}
+
+impl Instruction {
+    pub fn operands_size(&self) -> Word {
+        self.operands.iter().map(Operand::size).sum()
+    }
+
+    pub fn size(&self) -> Word {
+        1 + self.operands_size()
+    }
+
+    pub fn stack_change(&self) -> i8 {
+        self.pushes as i8 - self.pops as i8
+    }
+}
+
+macro_rules! instructions {
+    ($(($opcode:expr, $mnemonic:expr, [$($operand:ident),*], $takes:expr, $pops:expr, $pushes:expr)),*) => {
+        pub const INSTRUCTIONS: &[Instruction] = &[$(Instruction {
+            opcode: $opcode,
+            mnemonic: $mnemonic,
+            operands: &[$(Operand::$operand),*],
+            takes: $takes,
+            pops: $pops,
+            pushes: $pushes,
+        }),*];
+    }
+}
+
+instructions![
+    (NOP, "nop", [], 0, 0, 0),
+
+    (ADD, "add", [], 2, 2, 1),
+    (SUB, "sub", [], 2, 2, 1),
+    (MUL, "mul", [], 2, 2, 1),
+    (DIV, "div", [], 2, 2, 1),
+    (MOD, "mod", [], 2, 2, 1),
+    (INC, "inc", [], 1, 1, 1),
+    (DEC, "dec", [], 1, 1, 1),
+
+    (AND, "and", [], 2, 2, 1),
+    (OR, "or", [], 2, 2, 1),
+    (NOT, "not", [], 1, 1, 1),
+    (SHL, "shl", [Byte], 1, 1, 1),
+    (SHR, "shr", [Byte], 1, 1, 1),
+    (XOR, "xor", [], 2, 2, 1),
+
+    (PUSH, "push", [Byte], 0, 0, 1),
+    (POP, "pop", [], 1, 1, 0),
+    (SWP, "swp", [], 2, 2, 2),
+    (STORE, "store", [Data], 1, 0, 0),
+    (STORE_OFFS, "store_offs", [Data], 2, 0, 0),
+    (LOAD, "load", [Data], 0, 0, 1),
+    (LOAD_OFFS, "load_offs", [Data], 1, 0, 1),
+    (LOAD_PTR, "load_ptr", [], 3, 0, 1),
+    (STORE_PTR, "store_ptr", [], 4, 0, 0),
+
+    (JMP, "jmp", [Code], 0, 0, 0),
+    (JE, "je", [Code], 2, 0, 0),
+    (JNE, "jne", [Code], 2, 0, 0),
+    (JL, "jl", [Code], 2, 0, 0),
+    (JG, "jg", [Code], 2, 0, 0),
+    (JLE, "jle", [Code], 2, 0, 0),
+    (JGE, "jge", [Code], 2, 0, 0),
+
+    (CALL, "call", [Code], 0, 0, 0),
+    (RET, "ret", [], 0, 0, 0),
+    (SYSCALL, "syscall", [Byte], 0, 0, 0),
+    (RETI, "reti", [], 0, 0, 0),
+
+    (EMIT, "emit", [Event], 0, 0, 0),
+    (WAIT, "wait", [], 0, 0, 0),
+    (SUBSCRIBE, "subscribe", [Event, Handler], 0, 0, 0),
+    (UNSUBSCRIBE, "unsubscribe", [Event], 0, 0, 0),
+    (MASK, "mask", [Event], 0, 0, 0),
+    (UNMASK, "unmask", [Event], 0, 0, 0),
+    (MASK_ALL, "mask_all", [], 0, 0, 0),
+    (UNMASK_ALL, "unmask_all", [], 0, 0, 0),
+    (TIMER_SET, "timer_set", [Byte, Word], 0, 0, 0),
+    (TIMER_ONCE, "timer_once", [Byte, Word], 0, 0, 0),
+    (TIMER_STOP, "timer_stop", [Byte], 0, 0, 0),
+
+    (IN, "in", [Byte], 0, 0, 1),
+    (OUT, "out", [Byte], 1, 0, 0),
+    (WRITE, "write", [Data, Byte], 0, 0, 0),
+    (READ, "read", [Data, Byte], 0, 0, 1),
+
+    (ALLOC, "alloc", [], 2, 2, 2),
+    (FREE, "free", [], 2, 2, 0),
+    (BANK, "bank", [], 1, 1, 0)
+];
+
+pub fn instruction(opcode: u8) -> Option<&'static Instruction> {
+    INSTRUCTIONS.iter().find(|instruction| instruction.opcode == opcode)
+}
+
+pub fn by_mnemonic(mnemonic: &str) -> Option<&'static Instruction> {
+    INSTRUCTIONS.iter().find(|instruction| instruction.mnemonic == mnemonic)
+}
@@ -1,6 +1,31 @@
-pub const PC: u8 = 0x00;
-pub const IR: u8 = 0x01;
-pub const SP: u8 = 0x02;
-pub const RP: u8 = 0x03;
-pub const EP: u8 = 0x04;
-pub const EE: u8 = 0x05;
+use config::*;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Register {
+    PC = 0x00,
+    IR = 0x01,
+    SP = 0x02,
+    RP = 0x03,
+    EP = 0x04,
+    EE = 0x05,
+}
+
+pub use self::Register::*;
+
+/// The registers at some moment, see the table in README.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Registers {
+    pub pc: Word,
+    pub ir: Word,
+    pub sp: Word,
+    pub rp: Word,
+    pub ep: Word,
+    pub ee: Word,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Flags {
+    pub termination_scheduled: bool,
+    pub terminated: bool,
+    pub waiting: bool,
+}
@@ -0,0 +1,51 @@
+use config::*;
+use std::fmt;
+use vm::events::INTERRUPTED;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FaultKind {
+    Segfault,
+    UnknownError,
+}
+
+/// How the program has ended.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExitStatus {
+    /// Ran past the end of the code.
+    Finished,
+    /// TERMINATE with its argument.
+    Terminated(u8),
+    /// The host has asked the program to terminate (e.g. Ctrl-C) and it
+    /// has no TERMINATE handler.
+    Interrupted,
+    /// An unhandled SEGFAULT or UNKNOWN_ERROR raised by the instruction at
+    /// the given address.
+    Fault(FaultKind, Word),
+}
+
+impl ExitStatus {
+    pub fn code(&self) -> Option<u8> {
+        match *self {
+            ExitStatus::Finished => Some(0),
+            ExitStatus::Terminated(code) => Some(code),
+            ExitStatus::Interrupted => Some(INTERRUPTED),
+            ExitStatus::Fault(_, _) => None,
+        }
+    }
+
+    pub fn fault(&self) -> Option<FaultKind> {
+        match *self {
+            ExitStatus::Fault(kind, _) => Some(kind),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for FaultKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FaultKind::Segfault => write!(f, "Segfault"),
+            FaultKind::UnknownError => write!(f, "Unknown Error"),
+        }
+    }
+}
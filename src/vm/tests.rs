@@ -1,10 +1,35 @@
 use config::*;
+use std::cell::RefCell;
+use std::env;
+use std::fs as std_fs;
+use std::io::{self, ErrorKind, Read, Result, Write};
+use std::process;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::sleep;
+use std::time::Duration;
 use utils;
+use vm::devices::*;
+use vm::devices::fs::*;
+use vm::devices::console::*;
+use vm::devices::random::Random;
 use vm::events::*;
+use vm::journal::*;
 use vm::opcodes::*;
+use vm::heap::Heap;
+use vm::host::Signature;
+use vm::input::AsyncReader;
+use vm::memory::{Memory, Region};
 use vm::registers::*;
-
-#[cfg_attr(rustfmt, rustfmt_skip)]
+use vm::status::*;
+use vm::verifier::*;
+use vm::VM;
+use vm::asm;
+use vm::backend::{Backend, Program};
+use vm::builder::VmBuilder;
+
+#[rustfmt::skip]
 #[test]
 fn locals_stack() {
     {
@@ -107,8 +132,7 @@ fn locals_stack() {
     }
 }
 
-#[cfg_attr(rustfmt, rustfmt_skip)]
-#[ignore]
+#[rustfmt::skip]
 #[test]
 fn locals_stack_damage() {
     {
@@ -123,15 +147,15 @@ fn locals_stack_damage() {
         assert!(vm.locals_stack().is_empty());
         assert!(vm.return_stack().is_empty());
         assert!(vm.event_queue().is_empty());
-        assert_eq!(b"Segfault", output.as_slice());
+        assert!(output.is_empty());
+        assert_eq!(Some(FaultKind::Segfault), vm.exit_status().unwrap().fault());
     }
 
     {
         let command_size = 2;
         let executable_size = WORD_SIZE + LOCALS_STACK_SIZE * command_size;
         let executable_size = executable_size as usize;
-        let mut executable = Vec::with_capacity(executable_size);
-        executable.resize(executable_size, 0x00);
+        let mut executable = vec![0x00; executable_size];
 
         let mut i = 3;
         while i < executable_size {
@@ -152,8 +176,7 @@ fn locals_stack_damage() {
         let command_size = 2;
         let executable_size = WORD_SIZE + (LOCALS_STACK_SIZE + 1) * command_size;
         let executable_size = executable_size as usize;
-        let mut executable = Vec::with_capacity(executable_size);
-        executable.resize(executable_size, 0x00);
+        let mut executable = vec![0x00; executable_size];
 
         let mut i = 3;
         while i < executable_size {
@@ -167,7 +190,8 @@ fn locals_stack_damage() {
         assert_eq!(LOCALS_STACK_SIZE, vm.locals_stack().len() as Word);
         assert!(vm.return_stack().is_empty());
         assert!(vm.event_queue().is_empty());
-        assert_eq!(b"Segfault", output.as_slice());
+        assert!(output.is_empty());
+        assert_eq!(Some(FaultKind::Segfault), vm.exit_status().unwrap().fault());
     }
 
     {
@@ -183,7 +207,8 @@ fn locals_stack_damage() {
         assert_eq!(LOCALS_STACK_SIZE, vm.locals_stack().len() as Word);
         assert!(vm.return_stack().is_empty());
         assert!(vm.event_queue().is_empty());
-        assert_eq!(b"Segfault", output.as_slice());
+        assert!(output.is_empty());
+        assert_eq!(Some(FaultKind::Segfault), vm.exit_status().unwrap().fault());
     }
 
     {
@@ -198,7 +223,8 @@ fn locals_stack_damage() {
         assert!(vm.locals_stack().is_empty());
         assert!(vm.return_stack().is_empty());
         assert!(vm.event_queue().is_empty());
-        assert_eq!(b"Segfault", output.as_slice());
+        assert!(output.is_empty());
+        assert_eq!(Some(FaultKind::Segfault), vm.exit_status().unwrap().fault());
     }
 }
 
@@ -261,8 +287,7 @@ fn return_stack() {
     }
 }
 
-#[cfg_attr(rustfmt, rustfmt_skip)]
-#[ignore]
+#[rustfmt::skip]
 #[test]
 fn return_stack_damage() {
     {
@@ -277,7 +302,8 @@ fn return_stack_damage() {
         assert!(vm.locals_stack().is_empty());
         assert!(vm.return_stack().is_empty());
         assert!(vm.event_queue().is_empty());
-        assert_eq!(b"Segfault", output.as_slice());
+        assert!(output.is_empty());
+        assert_eq!(Some(FaultKind::Segfault), vm.exit_status().unwrap().fault());
     }
 
     {
@@ -292,7 +318,8 @@ fn return_stack_damage() {
         assert!(vm.locals_stack().is_empty());
         assert_eq!(RETURN_STACK_SIZE, vm.return_stack().len() as Word);
         assert!(vm.event_queue().is_empty());
-        assert_eq!(b"Segfault", output.as_slice());
+        assert!(output.is_empty());
+        assert_eq!(Some(FaultKind::Segfault), vm.exit_status().unwrap().fault());
     }
 }
 
@@ -340,7 +367,7 @@ fn event_queue() {
     assert!(output.is_empty());
 }
 
-#[cfg_attr(rustfmt, rustfmt_skip)]
+#[rustfmt::skip]
 #[test]
 fn load_store() {
     {
@@ -408,7 +435,8 @@ fn load_store() {
         assert!(vm.locals_stack().is_empty());
         assert!(vm.return_stack().is_empty());
         assert!(vm.event_queue().is_empty());
-        assert_eq!(b"Segfault", output.as_slice());
+        assert!(output.is_empty());
+        assert_eq!(Some(FaultKind::Segfault), vm.exit_status().unwrap().fault());
     }
 
     {
@@ -424,7 +452,8 @@ fn load_store() {
         assert!(vm.locals_stack().is_empty());
         assert!(vm.return_stack().is_empty());
         assert!(vm.event_queue().is_empty());
-        assert_eq!(b"Segfault", output.as_slice());
+        assert!(output.is_empty());
+        assert_eq!(Some(FaultKind::Segfault), vm.exit_status().unwrap().fault());
     }
 
     {
@@ -440,7 +469,8 @@ fn load_store() {
         assert!(vm.locals_stack().is_empty());
         assert!(vm.return_stack().is_empty());
         assert!(vm.event_queue().is_empty());
-        assert_eq!(b"Segfault", output.as_slice());
+        assert!(output.is_empty());
+        assert_eq!(Some(FaultKind::Segfault), vm.exit_status().unwrap().fault());
     }
 
     {
@@ -491,11 +521,12 @@ fn load_store() {
         assert_eq!(&[0x55], vm.locals_stack());
         assert!(vm.return_stack().is_empty());
         assert!(vm.event_queue().is_empty());
-        assert_eq!(b"Segfault", output.as_slice());
+        assert!(output.is_empty());
+        assert_eq!(Some(FaultKind::Segfault), vm.exit_status().unwrap().fault());
     }
 }
 
-#[cfg_attr(rustfmt, rustfmt_skip)]
+#[rustfmt::skip]
 #[test]
 fn arithmetic() {
     {
@@ -546,7 +577,8 @@ fn arithmetic() {
         assert_eq!(&[0x03], vm.locals_stack());
         assert!(vm.return_stack().is_empty());
         assert!(vm.event_queue().is_empty());
-        assert_eq!(b"Segfault", output.as_slice());
+        assert!(output.is_empty());
+        assert_eq!(Some(FaultKind::Segfault), vm.exit_status().unwrap().fault());
     }
 
     {
@@ -648,7 +680,8 @@ fn arithmetic() {
         assert!(vm.locals_stack().is_empty());
         assert!(vm.return_stack().is_empty());
         assert!(vm.event_queue().is_empty());
-        assert_eq!(b"Unknown Error", output.as_slice());
+        assert!(output.is_empty());
+        assert_eq!(Some(FaultKind::UnknownError), vm.exit_status().unwrap().fault());
     }
 
     {
@@ -682,7 +715,8 @@ fn arithmetic() {
         assert!(vm.locals_stack().is_empty());
         assert!(vm.return_stack().is_empty());
         assert!(vm.event_queue().is_empty());
-        assert_eq!(b"Unknown Error", output.as_slice());
+        assert!(output.is_empty());
+        assert_eq!(Some(FaultKind::UnknownError), vm.exit_status().unwrap().fault());
     }
 
     {
@@ -750,7 +784,7 @@ fn arithmetic() {
     }
 }
 
-#[cfg_attr(rustfmt, rustfmt_skip)]
+#[rustfmt::skip]
 #[test]
 fn arithmetic_missing_args() {
     for &opcode in &[ADD, SUB, DIV, MUL, MOD] {
@@ -767,7 +801,8 @@ fn arithmetic_missing_args() {
             assert_eq!(&[0x01], vm.locals_stack());
             assert!(vm.return_stack().is_empty());
             assert!(vm.event_queue().is_empty());
-            assert_eq!(b"Segfault", output.as_slice());
+            assert!(output.is_empty());
+            assert_eq!(Some(FaultKind::Segfault), vm.exit_status().unwrap().fault());
         }
     }
 
@@ -784,12 +819,13 @@ fn arithmetic_missing_args() {
             assert!(vm.locals_stack().is_empty());
             assert!(vm.return_stack().is_empty());
             assert!(vm.event_queue().is_empty());
-            assert_eq!(b"Segfault", output.as_slice());
+            assert!(output.is_empty());
+            assert_eq!(Some(FaultKind::Segfault), vm.exit_status().unwrap().fault());
         }
     }
 }
 
-#[cfg_attr(rustfmt, rustfmt_skip)]
+#[rustfmt::skip]
 #[test]
 fn bitwise() {
     {
@@ -839,7 +875,8 @@ fn bitwise() {
         assert_eq!(&[0x00], vm.locals_stack());
         assert!(vm.return_stack().is_empty());
         assert!(vm.event_queue().is_empty());
-        assert_eq!(b"Segfault", output.as_slice());
+        assert!(output.is_empty());
+        assert_eq!(Some(FaultKind::Segfault), vm.exit_status().unwrap().fault());
     }
 
     {
@@ -854,7 +891,8 @@ fn bitwise() {
         assert!(vm.locals_stack().is_empty());
         assert!(vm.return_stack().is_empty());
         assert!(vm.event_queue().is_empty());
-        assert_eq!(b"Segfault", output.as_slice());
+        assert!(output.is_empty());
+        assert_eq!(Some(FaultKind::Segfault), vm.exit_status().unwrap().fault());
     }
 
     {
@@ -967,7 +1005,8 @@ fn bitwise() {
         assert!(vm.locals_stack().is_empty());
         assert!(vm.return_stack().is_empty());
         assert!(vm.event_queue().is_empty());
-        assert_eq!(b"Segfault", output.as_slice());
+        assert!(output.is_empty());
+        assert_eq!(Some(FaultKind::Segfault), vm.exit_status().unwrap().fault());
     }
 
     {
@@ -1037,7 +1076,7 @@ fn bitwise() {
     }
 }
 
-#[cfg_attr(rustfmt, rustfmt_skip)]
+#[rustfmt::skip]
 #[test]
 fn jumps() {
     {
@@ -1138,7 +1177,7 @@ fn jumps() {
     }
 }
 
-#[cfg_attr(rustfmt, rustfmt_skip)]
+#[rustfmt::skip]
 #[test]
 fn functions() {
     {
@@ -1189,7 +1228,7 @@ fn functions() {
     }
 }
 
-#[cfg_attr(rustfmt, rustfmt_skip)]
+#[rustfmt::skip]
 #[test]
 fn events() {
     {
@@ -1253,11 +1292,12 @@ fn events() {
         assert!(vm.locals_stack().is_empty());
         assert!(vm.return_stack().is_empty());
         assert!(vm.event_queue().is_empty());
-        assert_eq!(b"Unknown Error", output.as_slice());
+        assert!(output.is_empty());
+        assert_eq!(Some(FaultKind::UnknownError), vm.exit_status().unwrap().fault());
     }
 }
 
-#[cfg_attr(rustfmt, rustfmt_skip)]
+#[rustfmt::skip]
 #[test]
 fn handle_events() {
     {
@@ -1285,7 +1325,8 @@ fn handle_events() {
         assert!(vm.locals_stack().is_empty());
         assert!(vm.return_stack().is_empty());
         assert!(vm.event_queue().is_empty());
-        assert_eq!(b"Unknown Error", output.as_slice());
+        assert!(output.is_empty());
+        assert_eq!(Some(FaultKind::UnknownError), vm.exit_status().unwrap().fault());
     }
 
     {
@@ -1311,7 +1352,7 @@ fn handle_events() {
         let (output, vm) = utils::test_run(&[], executable, 0);
 
         assert!(vm.data().is_empty());
-        assert_eq!(&[b'y'], vm.locals_stack());
+        assert_eq!(b"y", vm.locals_stack());
         assert!(vm.return_stack().is_empty());
         assert!(vm.event_queue().is_empty());
         assert_eq!(b"xy", output.as_slice());
@@ -1366,9 +1407,2136 @@ fn handle_events() {
         let (output, vm) = utils::test_run(&[], executable, 0);
 
         assert!(vm.data().is_empty());
-        assert_eq!(&[b'b'], vm.locals_stack());
+        assert_eq!(b"b", vm.locals_stack());
         assert!(vm.return_stack().is_empty());
         assert!(vm.event_queue().is_empty());
         assert!(output.as_slice().is_empty());
     }
 }
+
+#[rustfmt::skip]
+#[test]
+fn record_replay() {
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            EMIT, INPUT,
+            EMIT, OUTPUT,
+            EMIT, INPUT,
+            EMIT, OUTPUT];
+
+        let input = [0x41, 0x42];
+        let mut vm = utils::test_vm(&input, executable.clone(), 0);
+        vm.record();
+        vm.run();
+
+        let journal = vm.journal().clone();
+        assert_eq!(*journal.entries(),
+                   vec![Entry { step: 1, record: Record::Input(0x41) },
+                        Entry { step: 3, record: Record::Input(0x42) }]);
+
+        let mut replayed = utils::test_vm(&[], executable, 0);
+        replayed.replay(journal);
+        replayed.run();
+
+        let output = replayed.get_output_ref().get_ref();
+        assert_eq!(&[0x42, 0x41], replayed.locals_stack());
+        assert_eq!(&[0x41, 0x42], output.as_slice());
+        assert_eq!(vm.steps(), replayed.steps());
+        assert!(replayed.journal().is_empty());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            SUBSCRIBE, CLOCK, 0x0a, 0x00,
+
+                                           // loop:
+            NOP,
+            JMP, 0x06, 0x00,               // goto loop
+
+                                           // handler:
+            EMIT, OUTPUT,
+            POP,
+            RET];
+
+        let mut journal = Journal::new();
+        journal.push(3, Record::Clock);
+        journal.push(5, Record::Clock);
+        journal.push(11, Record::Terminate);
+
+        let mut vm = utils::test_vm(&[], executable, 0);
+        vm.replay(journal);
+        vm.run();
+
+        let output = vm.get_output_ref().get_ref();
+        assert!(vm.locals_stack().is_empty());
+        assert!(vm.return_stack().is_empty());
+        assert!(vm.event_queue().is_empty());
+        assert_eq!(&[0x00, 0x01], output.as_slice());
+        assert_eq!(11, vm.steps());
+        assert!(vm.journal().is_empty());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            SUBSCRIBE, TERMINATE, 0x0a, 0x00,
+            WAIT,
+            JMP, 0x06, 0x00,
+
+                                           // handler:
+            EMIT, OUTPUT,                  // INTERRUPTED
+            PUSH, b'\n',                   // flushes the output
+            EMIT, OUTPUT,
+            RETI];
+
+        let mut vm = utils::test_vm(&[], executable.clone(), 0);
+        vm.record();
+        vm.start();
+        assert!(vm.step());
+
+        vm.termination_scheduled.store(true, Ordering::Relaxed);
+        for _ in 0..16 {
+            assert!(vm.step());
+        }
+
+        let journal = vm.journal().clone();
+        assert_eq!(*journal.entries(),
+                   vec![Entry { step: 2, record: Record::Terminate }]);
+        assert_eq!(&[INTERRUPTED, b'\n'], vm.get_output_ref().get_ref().as_slice());
+
+        let mut replayed = utils::test_vm(&[], executable, 0);
+        replayed.replay(journal);
+        replayed.start();
+        for _ in 0..17 {
+            assert!(replayed.step());
+        }
+
+        assert_eq!(&[INTERRUPTED, b'\n'], replayed.get_output_ref().get_ref().as_slice());
+        assert!(replayed.journal().is_empty());
+    }
+}
+
+#[test]
+fn journal_serialization() {
+    let mut journal = Journal::new();
+    journal.push(1, Record::Input(0x7f));
+    journal.push(0x0102_0304_0506, Record::Clock);
+    journal.push(0x0102_0304_0507, Record::Terminate);
+
+    let mut serialized = vec![];
+    journal.save(&mut serialized).unwrap();
+    assert_eq!(3 * 10, serialized.len());
+
+    let loaded = Journal::load(&mut serialized.as_slice()).unwrap();
+    assert_eq!(journal, loaded);
+
+    let mut truncated = &serialized[..serialized.len() - 1];
+    assert!(Journal::load(&mut truncated).is_err());
+
+    let mut trailing = serialized.clone();
+    trailing.extend_from_slice(&[0x00, 0x00, 0x00]);
+    let error = Journal::load(&mut trailing.as_slice()).err().unwrap();
+    assert_eq!(ErrorKind::InvalidData, error.kind());
+
+    let mut corrupted = serialized.clone();
+    corrupted[8] = 0xff;
+    assert!(Journal::load(&mut corrupted.as_slice()).is_err());
+}
+
+#[rustfmt::skip]
+#[test]
+fn history() {
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            PUSH, 0x01,
+            PUSH, 0x02,
+            ADD,
+            STORE, 0x0d, 0x00,
+            LOAD, 0xff, 0xff,              // access violation
+            0x00];
+
+        let mut vm = utils::test_vm(&[], executable, 1);
+        vm.enable_history(16);
+        vm.run();
+
+        assert_eq!(&[0x03], vm.data());
+        assert_eq!(&[0x03], vm.locals_stack());
+        assert_eq!(5, vm.steps());
+
+        assert!(vm.rewind_to_write(0x0d));
+        assert_eq!(&[0x00], vm.data());
+        assert_eq!(&[0x03], vm.locals_stack());
+        assert_eq!(0x07, vm.get_register(PC));
+        assert_eq!(3, vm.steps());
+
+        assert!(vm.step_back());
+        assert_eq!(&[0x02, 0x01], vm.locals_stack());
+        assert_eq!(0x06, vm.get_register(PC));
+
+        assert!(vm.step());
+        assert!(vm.step());
+        assert_eq!(&[0x03], vm.data());
+        assert!(!vm.step());
+        assert!(vm.get_output_ref().get_ref().is_empty());
+        assert_eq!(Some(FaultKind::Segfault), vm.exit_status().unwrap().fault());
+
+        assert!(!vm.rewind_to_write(0x0e));
+        assert!(!vm.rewind_to_call());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            PUSH, 0x00,
+            CALL, 0x09, 0x00,              // call f
+            EMIT, TERMINATE,
+
+            PUSH, 0x01,                    // f:
+            DIV,                           // div by zero
+            RET];
+
+        let mut vm = utils::test_vm(&[], executable, 0);
+        vm.enable_history(16);
+        vm.run();
+
+        assert!(vm.locals_stack().is_empty());
+        assert_eq!(&[0x07, 0x00], vm.return_stack());
+
+        assert!(vm.rewind_to_call());
+        assert_eq!(0x04, vm.get_register(PC));
+        assert_eq!(&[0x00], vm.locals_stack());
+        assert!(vm.return_stack().is_empty());
+        assert_eq!(1, vm.steps());
+
+        assert!(vm.step_back());
+        assert!(vm.locals_stack().is_empty());
+        assert_eq!(CODE_OFFSET, vm.get_register(PC));
+        assert!(!vm.step_back());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            PUSH, 0x01,
+            PUSH, 0x02,
+            PUSH, 0x03];
+
+        let mut vm = utils::test_vm(&[], executable, 0);
+        vm.enable_history(2);
+        vm.run();
+
+        assert!(vm.step_back());
+        assert!(vm.step_back());
+        assert!(!vm.step_back());
+        assert_eq!(&[0x02, 0x01], vm.locals_stack());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            EMIT, INPUT,
+            EMIT, INPUT,
+            EMIT, INPUT];                  // end of the input
+
+        let mut vm = utils::test_vm(b"xy", executable, 0);
+        vm.enable_history(16);
+        vm.run();
+        assert_eq!(&[0x00, b'y', b'x'], vm.locals_stack());
+
+        while vm.step_back() {}
+        assert!(vm.locals_stack().is_empty());
+        assert_eq!(CODE_OFFSET, vm.get_register(PC));
+
+        while vm.step() {}
+        assert_eq!(&[0x00, b'y', b'x'], vm.locals_stack());
+
+        assert!(vm.rewind_to_write(vm.get_register(SP) + 1));
+        assert_eq!(b"x", vm.locals_stack());
+        while vm.step() {}
+        assert_eq!(&[0x00, b'y', b'x'], vm.locals_stack());
+    }
+}
+
+struct Latch {
+    written: Rc<RefCell<Data>>,
+}
+
+impl Device for Latch {
+    fn read(&mut self, offset: Word) -> u8 {
+        0x10 + offset as u8
+    }
+
+    fn write(&mut self, offset: Word, value: u8) {
+        self.written.borrow_mut().push(offset as u8);
+        self.written.borrow_mut().push(value);
+    }
+}
+
+#[rustfmt::skip]
+#[test]
+fn devices() {
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            IN, 0x20,
+            IN, 0x21,
+            OUT, 0x20,
+            PUSH, 0x33,
+            OUT, 0x21,
+            IN, 0x22];                     // unmapped port
+
+        let written = Rc::new(RefCell::new(vec![]));
+        let latch = Latch { written: written.clone() };
+
+        let mut vm = utils::test_vm(&[], executable, 0);
+        assert!(vm.attach_device(Mapping::Ports(0x20, 2), Box::new(latch)));
+        vm.run();
+
+        let output = vm.get_output_ref().get_ref();
+        assert_eq!(&[0x33, 0x11, 0x10], vm.locals_stack());
+        assert_eq!(&[0x00, 0x11, 0x01, 0x33], written.borrow().as_slice());
+        assert!(output.is_empty());
+        assert_eq!(Some(FaultKind::Segfault), vm.exit_status().unwrap().fault());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            LOAD, 0x00, 0xf0,
+            PUSH, 0x02,
+            LOAD_OFFS, 0x00, 0xf0,
+            STORE, 0x03, 0xf0,
+            STORE, 0x04, 0xf0];            // unmapped address
+
+        let written = Rc::new(RefCell::new(vec![]));
+        let latch = Latch { written: written.clone() };
+
+        let mut vm = utils::test_vm(&[], executable, 0);
+        assert!(vm.attach_device(Mapping::Memory(0xf000, 4), Box::new(latch)));
+        vm.run();
+
+        let output = vm.get_output_ref().get_ref();
+        assert_eq!(&[0x12, 0x02, 0x10], vm.locals_stack());
+        assert_eq!(&[0x03, 0x12], written.borrow().as_slice());
+        assert!(output.is_empty());
+        assert_eq!(Some(FaultKind::Segfault), vm.exit_status().unwrap().fault());
+    }
+
+    {
+        let mut vm = utils::test_vm(&[], vec![0x00, 0x00], 0);
+        assert!(vm.attach_device(Mapping::Ports(0x00, 4), Box::new(Random::new(1))));
+        assert!(!vm.attach_device(Mapping::Ports(0x03, 1), Box::new(Random::new(1))));
+        assert!(vm.attach_device(Mapping::Ports(0x04, 1), Box::new(Random::new(1))));
+        assert!(vm.attach_device(Mapping::Memory(0xff00, 0x100), Box::new(Random::new(1))));
+        assert!(!vm.attach_device(Mapping::Memory(0xfff0, 0x20), Box::new(Random::new(1))));
+        assert!(!vm.attach_device(Mapping::Memory(0x0000, 0x01), Box::new(Random::new(1))));
+    }
+
+    {
+        let mut random = Random::new(0x1234);
+        let first = (0..4).map(|_| random.read(0)).collect::<Data>();
+        random.write(0, 0x34);
+        let second = (0..4).map(|_| random.read(0)).collect::<Data>();
+        assert_ne!(first, second);
+
+        random.write(0, 0x34);
+        let third = (0..4).map(|_| random.read(0)).collect::<Data>();
+        assert_eq!(second, third);
+    }
+
+    {
+        let mut console = Console::new(&b"a"[..], io::sink());
+
+        let mut status = console.read(CONSOLE_STATUS);
+        while status == CONSOLE_EMPTY {
+            sleep(Duration::from_millis(1));
+            status = console.read(CONSOLE_STATUS);
+        }
+        assert_eq!(CONSOLE_READY, status);
+        assert_eq!(b'a', console.read(CONSOLE_DATA));
+
+        while status != CONSOLE_EOF {
+            sleep(Duration::from_millis(1));
+            status = console.read(CONSOLE_STATUS);
+        }
+        assert_eq!(0x00, console.read(CONSOLE_DATA));
+        assert_eq!(CONSOLE_EOF, console.read(CONSOLE_STATUS));
+    }
+}
+
+#[rustfmt::skip]
+#[test]
+fn host_functions() {
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            PUSH, 0xff,
+            PUSH, 0x02,
+            SYSCALL, 0x03,                 // widening add
+            SYSCALL, 0x04,                 // store the sum
+            SYSCALL, 0x05,
+            PUSH, b'z',                    // unreachable code
+            EMIT, OUTPUT,
+            0x00, 0x00];
+
+        let mut vm = utils::test_vm(&[], executable, 2);
+        vm.register_host_fn(0x03, Signature { pops: 2, pushes: 2 }, |ctx| {
+            let x = ctx.pop().unwrap() as Word;
+            let y = ctx.pop().unwrap() as Word;
+            assert!(ctx.push_word(x + y));
+        });
+        vm.register_host_fn(0x04, Signature { pops: 2, pushes: 0 }, |ctx| {
+            let sum = ctx.pop_word().unwrap();
+            assert!(ctx.store(0x10, sum as u8));
+            assert!(ctx.store(0x11, (sum >> 8) as u8));
+            assert!(!ctx.store(0x12, 0xff));
+            assert_eq!(Some(0x01), ctx.load(0x11));
+            assert_eq!(None, ctx.load(0x02));
+            ctx.emit(OUTPUT, b'x');
+        });
+        vm.register_host_fn(0x05, Signature { pops: 0, pushes: 0 }, |ctx| {
+            assert_eq!(None, ctx.pop());
+            ctx.fault();
+        });
+        vm.run();
+
+        let output = vm.get_output_ref().get_ref();
+        assert_eq!(&[0x01, 0x01], vm.data());
+        assert!(vm.locals_stack().is_empty());
+        assert!(vm.return_stack().is_empty());
+        assert!(vm.event_queue().is_empty());
+        assert_eq!(b"x", output.as_slice());
+        assert_eq!(Some(FaultKind::Segfault), vm.exit_status().unwrap().fault());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            SYSCALL, 0x01];                // not registered
+
+        let (output, vm) = utils::test_run(&[], executable, 0);
+
+        assert!(vm.locals_stack().is_empty());
+        assert!(output.is_empty());
+        assert_eq!(Some(FaultKind::UnknownError), vm.exit_status().unwrap().fault());
+    }
+}
+
+#[rustfmt::skip]
+#[test]
+fn user_events() {
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            SUBSCRIBE, 0x10, 0x12, 0x00,
+            PUSH, b'a',
+            EMIT, 0x10,
+            PUSH, b'b',
+            EMIT, 0x1f,                    // nobody is subscribed
+            EMIT, 0x10,
+            EMIT, TERMINATE,
+
+                                           // handler:
+            EMIT, OUTPUT,
+            POP,
+            RET];
+
+        let (output, vm) = utils::test_run(&[], executable, 0);
+
+        assert_eq!(b"ba", vm.locals_stack());
+        assert!(vm.return_stack().is_empty());
+        assert!(vm.event_queue().is_empty());
+        assert_eq!(b"ab", output.as_slice());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            EMIT, 0x20];                   // out of the handler table
+
+        let (output, vm) = utils::test_run(&[], executable, 0);
+        assert!(output.is_empty());
+        assert_eq!(Some(FaultKind::Segfault), vm.exit_status().unwrap().fault());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            SUBSCRIBE, 0x10, 0xff, 0x00];  // handler outside of code
+
+        let (output, vm) = utils::test_run(&[], executable, 0);
+        assert!(output.is_empty());
+        assert_eq!(Some(FaultKind::Segfault), vm.exit_status().unwrap().fault());
+    }
+
+    assert!(!is_user_defined(CLOCK));
+    assert!(is_user_defined(USER_EVENTS_BEGIN));
+    assert!(!is_user_defined(EVENT_HANDLERS as u8));
+    assert!(!is_critical(INPUT));
+    assert!(is_critical(UNKNOWN_ERROR));
+}
+
+#[test]
+fn event_priorities() {
+    {
+        let executable = vec![0x00, 0x00];
+
+        let (_, mut vm) = utils::test_run(&[], executable, 0);
+        vm.set_event_priority(INPUT, HIGH_PRIORITY);
+        vm.set_event_priority(0x10, LOW_PRIORITY);
+
+        vm.event_queue_push(0x10, 0x01);
+        vm.event_queue_push(CLOCK, 0x02);
+        vm.event_queue_push(INPUT, 0x03);
+        vm.event_queue_push(OUTPUT, 0x04);
+        vm.event_queue_push(INPUT, 0x05);
+
+        assert_eq!(&[0x10, 0x01, OUTPUT, 0x04, CLOCK, 0x02, INPUT, 0x05, INPUT, 0x03],
+                   vm.event_queue());
+
+        assert_eq!((INPUT, 0x03), vm.event_queue_pop());
+        assert_eq!((INPUT, 0x05), vm.event_queue_pop());
+        assert_eq!((CLOCK, 0x02), vm.event_queue_pop());
+        assert_eq!((OUTPUT, 0x04), vm.event_queue_pop());
+        assert_eq!((0x10, 0x01), vm.event_queue_pop());
+        assert!(vm.event_queue().is_empty());
+    }
+
+    {
+        // a low priority event starves while high priority ones keep coming
+        let executable = vec![0x00, 0x00];
+
+        let (_, mut vm) = utils::test_run(&[], executable, 0);
+        vm.set_event_priority(INPUT, HIGH_PRIORITY);
+
+        vm.event_queue_push(CLOCK, 0x00);
+        for i in 0..100 {
+            vm.event_queue_push(INPUT, i);
+            assert_eq!((INPUT, i), vm.event_queue_pop());
+            assert_eq!(&[CLOCK, 0x00], vm.event_queue());
+        }
+
+        assert_eq!((CLOCK, 0x00), vm.event_queue_pop());
+        assert!(vm.event_queue().is_empty());
+    }
+
+    {
+        // a full queue drops the newest of the lowest priority events
+        let executable = vec![0x00, 0x00];
+
+        let (_, mut vm) = utils::test_run(&[], executable, 0);
+        vm.set_event_priority(INPUT, HIGH_PRIORITY);
+        vm.set_event_priority(0x10, LOW_PRIORITY);
+
+        let capacity = EVENT_QUEUE_SIZE / EVENT_SIZE;
+        for i in 0..capacity {
+            vm.event_queue_push(CLOCK, i as u8);
+        }
+        assert_eq!(EVENT_QUEUE_SIZE, vm.event_queue().len() as Word);
+
+        vm.event_queue_push(0x10, 0xff);
+        vm.event_queue_push(CLOCK, 0xff);
+        assert_eq!(&[CLOCK, capacity as u8 - 1], &vm.event_queue()[..2]);
+
+        assert_eq!((CLOCK, 0x00), vm.event_queue_pop());
+        vm.event_queue_push(INPUT, 0x01);
+        vm.event_queue_push(INPUT, 0x02);
+        assert_eq!(EVENT_QUEUE_SIZE, vm.event_queue().len() as Word);
+
+        assert_eq!((INPUT, 0x01), vm.event_queue_pop());
+        assert_eq!((INPUT, 0x02), vm.event_queue_pop());
+        for i in 1..(capacity - 1) {
+            assert_eq!((CLOCK, i as u8), vm.event_queue_pop());
+        }
+        assert!(vm.event_queue().is_empty());
+    }
+}
+
+#[rustfmt::skip]
+#[test]
+fn event_priorities_dispatch() {
+    let executable = vec![
+        0x00, 0x00,
+
+        SUBSCRIBE, 0x10, 0x0e, 0x00,
+        SUBSCRIBE, 0x11, 0x0e, 0x00,
+        SYSCALL, 0x00,                     // emit a burst of events
+        EMIT, TERMINATE,
+
+                                           // handler:
+        EMIT, OUTPUT,
+        POP,
+        RET];
+
+    let mut vm = utils::test_vm(&[], executable, 0);
+    vm.set_event_priority(0x11, HIGH_PRIORITY);
+    vm.register_host_fn(0x00, Signature { pops: 0, pushes: 0 }, |ctx| {
+        ctx.emit(0x10, b'a');
+        ctx.emit(0x10, b'b');
+        ctx.emit(0x11, b'c');
+        ctx.emit(0x10, b'd');
+        ctx.emit(0x11, b'e');
+    });
+    vm.run();
+
+    let output = vm.get_output_ref().get_ref();
+    assert!(vm.event_queue().is_empty());
+    assert_eq!(b"ceabd", output.as_slice());
+}
+
+#[rustfmt::skip]
+#[test]
+fn event_masking() {
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            SUBSCRIBE, 0x10, 0x0a, 0x00,
+            SYSCALL, 0x00,                 // emit a burst of events
+            EMIT, TERMINATE,
+
+                                           // handler:
+            EMIT, OUTPUT,                  // not interrupted by the next event
+            POP,
+            PUSH, b'-',
+            EMIT, OUTPUT,
+            POP,
+            RETI];
+
+        let mut vm = utils::test_vm(&[], executable, 0);
+        vm.register_host_fn(0x00, Signature { pops: 0, pushes: 0 }, |ctx| {
+            ctx.emit(0x10, b'a');
+            ctx.emit(0x10, b'b');
+        });
+        vm.run();
+
+        let output = vm.get_output_ref().get_ref();
+        assert!(vm.locals_stack().is_empty());
+        assert!(vm.return_stack().is_empty());
+        assert!(vm.event_queue().is_empty());
+        assert!(vm.handler_frames.is_empty());
+        assert!(vm.events_enabled);
+        assert_eq!(b"a-b-", output.as_slice());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            SUBSCRIBE, 0x10, 0x14, 0x00,
+            SUBSCRIBE, 0x11, 0x14, 0x00,
+            MASK, 0x10,
+            SYSCALL, 0x00,
+            NOP,
+            NOP,
+            UNMASK, 0x10,
+            EMIT, TERMINATE,
+
+                                           // handler:
+            EMIT, OUTPUT,
+            POP,
+            RETI];
+
+        let mut vm = utils::test_vm(&[], executable, 0);
+        vm.register_host_fn(0x00, Signature { pops: 0, pushes: 0 }, |ctx| {
+            ctx.emit(0x10, b'x');
+            ctx.emit(0x11, b'y');
+        });
+        vm.run();
+
+        let output = vm.get_output_ref().get_ref();
+        assert!(vm.locals_stack().is_empty());
+        assert!(vm.event_queue().is_empty());
+        assert_eq!(b"yx", output.as_slice());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            SUBSCRIBE, 0x10, 0x12, 0x00,
+            MASK_ALL,
+            PUSH, b'z',
+            EMIT, 0x10,
+            EMIT, OUTPUT,                  // handled by the VM itself
+            POP,
+            UNMASK_ALL,
+            EMIT, TERMINATE,
+            NOP,
+
+                                           // handler:
+            EMIT, OUTPUT,
+            POP,
+            RET];                          // restores the mask as well
+
+        let (output, vm) = utils::test_run(&[], executable, 0);
+
+        assert!(vm.locals_stack().is_empty());
+        assert!(vm.return_stack().is_empty());
+        assert!(vm.event_queue().is_empty());
+        assert!(vm.handler_frames.is_empty());
+        assert_eq!(b"zz", output.as_slice());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            SUBSCRIBE, UNKNOWN_ERROR, 0x0e, 0x00,
+            MASK_ALL,
+            PUSH, 0x00,
+            PUSH, 0x01,
+            DIV,                           // critical events can't be masked
+            EMIT, TERMINATE,
+
+                                           // handler:
+            PUSH, b'x',
+            EMIT, OUTPUT,
+            POP,
+            POP,
+            RETI];
+
+        let (output, vm) = utils::test_run(&[], executable, 0);
+
+        assert!(vm.locals_stack().is_empty());
+        assert!(vm.return_stack().is_empty());
+        assert!(!vm.events_enabled);
+        assert_eq!(b"x", output.as_slice());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            CALL, 0x06, 0x00,
+            NOP,
+            RETI];                         // not in a handler
+
+        let (output, vm) = utils::test_run(&[], executable, 0);
+
+        assert!(vm.return_stack().is_empty());
+        assert!(output.is_empty());
+        assert_eq!(Some(FaultKind::Segfault), vm.exit_status().unwrap().fault());
+    }
+}
+
+#[rustfmt::skip]
+#[test]
+fn handler_context() {
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            PUSH, 0x07,
+            SUBSCRIBE, 0x10, 0x0f, 0x00,
+            PUSH, b'a',
+            EMIT, 0x10,
+            POP,
+            EMIT, TERMINATE,
+
+                                           // handler:
+            PUSH, 0x01,
+            PUSH, 0x02,
+            EMIT, OUTPUT,
+            RETI];                         // drops 'a', 0x01 and 0x02
+
+        let (output, vm) = utils::test_run(&[], executable, 0);
+
+        assert_eq!(&[0x07], vm.locals_stack());
+        assert!(vm.return_stack().is_empty());
+        assert!(vm.event_queue().is_empty());
+        assert!(vm.handler_frames.is_empty());
+        assert_eq!(&[0x02], output.as_slice());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            SUBSCRIBE, TERMINATE, 0x09, 0x00,
+            WAIT,
+            EMIT, TERMINATE,
+
+                                           // handler:
+            PUSH, b'\n',                   // flushes the output
+            EMIT, OUTPUT,
+            RETI];                         // back to waiting
+
+        let mut journal = Journal::new();
+        journal.push(2, Record::Terminate);
+
+        let mut vm = utils::test_vm(&[], executable, 0);
+        vm.replay(journal);
+        vm.start();
+
+        for _ in 0..6 {
+            assert!(vm.step());
+        }
+
+        assert!(vm.waiting);
+        assert_eq!(0x07, vm.get_register(PC));
+        assert!(vm.locals_stack().is_empty());
+        assert!(vm.return_stack().is_empty());
+        assert!(vm.handler_frames.is_empty());
+        assert_eq!(b"\n", vm.get_output_ref().get_ref().as_slice());
+    }
+}
+
+#[rustfmt::skip]
+#[test]
+fn timers() {
+    let executable = vec![
+        0x00, 0x00,
+
+        SUBSCRIBE, TIMER, 0x1c, 0x00,
+        SUBSCRIBE, TIMER + 1, 0x1e, 0x00,
+        TIMER_ONCE, 0x00, 0x64, 0x00,      // 100 ms
+        TIMER_SET, 0x01, 0x0a, 0x00,       // every 10 ms
+        TIMER_SET, 0x02, 0x01, 0x00,
+        TIMER_STOP, 0x02,                  // never fires
+
+                                           // loop:
+        WAIT,
+        JMP, 0x18, 0x00,
+
+                                           // timeout:
+        EMIT, TERMINATE,
+
+                                           // tick:
+        EMIT, OUTPUT,
+        RETI];
+
+    {
+        let mut vm = utils::test_vm(&[], executable.clone(), 0);
+        vm.record();
+        vm.run();
+
+        let output = vm.get_output_ref().get_ref().clone();
+        assert!(!output.is_empty());
+        assert_eq!((0..output.len() as u8).collect::<Vec<u8>>(), output);
+
+        let journal = vm.journal().clone();
+        let mut vm = utils::test_vm(&[], executable.clone(), 0);
+        vm.replay(journal);
+        vm.run();
+
+        assert_eq!(&output, vm.get_output_ref().get_ref());
+        assert!(vm.journal().is_empty());
+    }
+
+    {
+        let mut journal = Journal::new();
+        journal.push(5, Record::Timer(0x01));
+        journal.push(7, Record::Timer(0x01));
+        journal.push(7, Record::Timer(0x02));      // stopped
+        journal.push(9, Record::Timer(0x00));
+
+        let mut vm = utils::test_vm(&[], executable.clone(), 0);
+        vm.replay(journal);
+        vm.run();
+
+        let output = vm.get_output_ref().get_ref();
+        assert!(vm.timers.iter().all(|timer| timer.is_none_or(|timer| timer.periodic)));
+        assert_eq!(&[0x00, 0x01], output.as_slice());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            TIMER_SET, TIMERS, 0x01, 0x00];
+
+        let (output, vm) = utils::test_run(&[], executable, 0);
+        assert!(output.is_empty());
+        assert_eq!(Some(FaultKind::Segfault), vm.exit_status().unwrap().fault());
+    }
+}
+
+#[rustfmt::skip]
+#[test]
+fn async_input() {
+    struct SlowReader {
+        data: Data,
+    }
+
+    impl Read for SlowReader {
+        fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+            sleep(Duration::from_millis(50));
+            if self.data.is_empty() {
+                Ok(0)
+            } else {
+                buffer[0] = self.data.remove(0);
+                Ok(1)
+            }
+        }
+    }
+
+    fn async_vm<R>(input: R, mut executable: Data) -> VM<AsyncReader, Data>
+        where R: Read + Send + 'static
+    {
+        let code_size = executable.len() as Word - CODE_OFFSET;
+        Memory::write_word(&mut executable, 0, code_size);
+
+        let input = AsyncReader::new(input);
+        let termination_scheduled = Arc::new(AtomicBool::new(false));
+        VM::new(input, vec![], executable, termination_scheduled).unwrap()
+    }
+
+    {
+        let mut input = AsyncReader::new(SlowReader { data: vec![0x01, 0x02] });
+        let mut buffer = [0; 4];
+        let mut received = vec![];
+
+        loop {
+            match input.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(count) => received.extend_from_slice(&buffer[..count]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    sleep(Duration::from_millis(1))
+                }
+                Err(e) => panic!("{}", e),
+            }
+        }
+
+        assert_eq!(vec![0x01, 0x02], received);
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            SUBSCRIBE, INPUT, 0x0e, 0x00,
+            SUBSCRIBE, INPUT_EOF, 0x11, 0x00,
+
+                                           // loop:
+            WAIT,
+            JMP, 0x0a, 0x00,
+
+                                           // on_input:
+            EMIT, OUTPUT,
+            RETI,
+
+                                           // on_eof:
+            EMIT, TERMINATE];
+
+        let mut vm = async_vm(&b"abc"[..], executable.clone());
+        vm.run();
+
+        assert!(vm.event_queue().is_empty());
+        assert_eq!(b"abc", vm.get_output_ref().as_slice());
+
+        // the recorded input hadn't ended when the run was interrupted
+        let mut journal = Journal::new();
+        journal.push(3, Record::Input(b'a'));
+        journal.push(7, Record::Terminate);
+
+        let mut replayed = utils::test_vm(&[], executable, 0);
+        replayed.replay(journal);
+
+        assert_eq!(ExitStatus::Interrupted, replayed.run());
+        assert_eq!(b"a", replayed.get_output_ref().get_ref().as_slice());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            SUBSCRIBE, TIMER, 0x12, 0x00,
+            TIMER_SET, 0x00, 0x05, 0x00,
+            EMIT, INPUT,                   // the timer keeps ticking meanwhile
+            TIMER_STOP, 0x00,
+            EMIT, OUTPUT,
+            EMIT, TERMINATE,
+
+                                           // tick:
+            PUSH, b'.',
+            EMIT, OUTPUT,
+            RETI];
+
+        let mut vm = async_vm(SlowReader { data: vec![b'x'] }, executable);
+        vm.run();
+
+        let output = vm.get_output_ref();
+        assert_eq!(b"x", vm.locals_stack());
+        assert!(output.len() > 1);
+        assert!(output[..output.len() - 1].iter().all(|&value| value == b'.'));
+        assert_eq!(Some(&b'x'), output.last());
+    }
+
+    {
+        let code = vec![
+            READ, 0x0a, 0x00, 0x02,        // held back until 'a' arrives
+            WRITE, 0x0a, 0x00, 0x02];
+
+        let mut vm = VmBuilder::new()
+            .code(&code)
+            .data(b"..")
+            .input(AsyncReader::new(SlowReader { data: b"ab".to_vec() }))
+            .output(vec![])
+            .build()
+            .unwrap();
+        vm.enable_history(16);
+        vm.run();
+
+        assert_eq!(&[0x01], vm.locals_stack());
+        assert_eq!(b"a.", vm.get_output_ref().as_slice());
+        assert_eq!(2, vm.steps());
+
+        let mut undone = 0;
+        while vm.step_back() {
+            undone += 1;
+        }
+        assert_eq!(3, undone);             // READ, WRITE and the end of the code
+        assert!(vm.locals_stack().is_empty());
+        assert_eq!(CODE_OFFSET, vm.get_register(PC));
+    }
+}
+
+#[rustfmt::skip]
+#[test]
+fn block_io() {
+    {
+        let mut executable = vec![
+            0x00, 0x00,
+
+            WRITE, 0x06, 0x00, 0x0d];
+        executable.extend_from_slice(b"Hello World!\n");
+
+        let (output, vm) = utils::test_run(&[], executable, 13);
+
+        assert!(vm.locals_stack().is_empty());
+        assert!(vm.event_queue().is_empty());
+        assert_eq!(b"Hello World!\n", output.as_slice());
+    }
+
+    {
+        let mut executable = vec![
+            0x00, 0x00,
+
+            READ, 0x0e, 0x00, 0x04,
+            READ, 0x12, 0x00, 0x02,        // hits the end of input
+            WRITE, 0x0e, 0x00, 0x06];
+        executable.extend_from_slice(b"......");
+
+        let (output, vm) = utils::test_run(b"abcde", executable, 6);
+
+        assert_eq!(b"abcde.", vm.data());
+        assert_eq!(&[0x01, 0x04], vm.locals_stack());
+        assert_eq!(b"abcde.", output.as_slice());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            WRITE, 0x06, 0x00, 0x02,
+            0x00];
+
+        let (output, vm) = utils::test_run(&[], executable, 1);
+        assert!(output.is_empty());
+        assert_eq!(Some(FaultKind::Segfault), vm.exit_status().unwrap().fault());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            READ, 0xff, 0xff, 0x02];
+
+        let (output, vm) = utils::test_run(b"ab", executable, 0);
+        assert!(output.is_empty());
+        assert_eq!(Some(FaultKind::Segfault), vm.exit_status().unwrap().fault());
+    }
+}
+
+#[rustfmt::skip]
+#[test]
+fn filesystem() {
+    {
+        let mut executable = vec![
+            0x00, 0x00,
+
+            PUSH, 0x00,
+            PUSH, 0x1e,                    // name
+            PUSH, WRITE_MODE,
+            SYSCALL, FS_OPEN,
+            STORE, 0x25, 0x00,             // fd
+            POP,
+            PUSH, 0x03,                    // length
+            PUSH, 0x00,
+            PUSH, 0x22,                    // text
+            LOAD, 0x25, 0x00,
+            SYSCALL, FS_WRITE,
+            LOAD, 0x25, 0x00,
+            SYSCALL, FS_CLOSE];
+        executable.extend_from_slice(b"log\0hi!\0");
+
+        let storage = Rc::new(RefCell::new(MemoryStorage::new()));
+        let mut vm = utils::test_vm(&[], executable, 8);
+        vm.mount(Filesystem::new(Box::new(storage.clone()), Quota::default()).unwrap());
+        vm.run();
+
+        assert_eq!(&[FS_OK, 0x03], vm.locals_stack());
+        assert_eq!(Some(&b"hi!".to_vec()), storage.borrow().files.get("log"));
+    }
+
+    {
+        let mut executable = vec![
+            0x00, 0x00,
+
+            PUSH, 0x00,
+            PUSH, 0x1d,                    // name
+            PUSH, READ_MODE,
+            SYSCALL, FS_OPEN,
+            STORE, 0x28, 0x00,             // fd
+            POP,
+            PUSH, 0x08,                    // length
+            PUSH, 0x00,
+            PUSH, 0x20,                    // buffer
+            LOAD, 0x28, 0x00,
+            SYSCALL, FS_READ,
+            WRITE, 0x20, 0x00, 0x05];
+        executable.extend_from_slice(b"in\0........\0");
+
+        let mut storage = MemoryStorage::new();
+        storage.files.insert("in".to_string(), b"hello".to_vec());
+
+        let mut vm = utils::test_vm(&[], executable, 12);
+        vm.mount(Filesystem::new(Box::new(storage), Quota::default()).unwrap());
+        vm.run();
+
+        assert_eq!(&[0x05], vm.locals_stack());
+        assert_eq!(b"hello", vm.get_output_ref().get_ref().as_slice());
+    }
+
+    {
+        let mut executable = vec![
+            0x00, 0x00,
+
+            PUSH, 0x00,
+            PUSH, 0x19,                    // name
+            PUSH, WRITE_MODE,
+            SYSCALL, FS_OPEN,
+            STORE, 0x1d, 0x00,             // fd
+            POP,
+            PUSH, 0xff,                    // length
+            PUSH, 0x00,
+            PUSH, 0x1e,                    // text
+            LOAD, 0x1d, 0x00,
+            SYSCALL, FS_WRITE];
+        executable.extend_from_slice(b"big\0\0");
+        executable.extend_from_slice(&[b'x'; 0xff]);
+
+        let storage = Rc::new(RefCell::new(MemoryStorage::new()));
+        let mut vm = utils::test_vm(&[], executable, 5 + 0xff);
+        vm.mount(Filesystem::new(Box::new(storage.clone()), Quota::default()).unwrap());
+        vm.run();
+
+        assert_eq!(&[MAX_TRANSFER], vm.locals_stack());  // not FS_ERROR
+        drop(vm);
+        assert_eq!(Some(MAX_TRANSFER as usize),
+                   storage.borrow().files.get("big").map(|data| data.len()));
+
+        let quota = Quota {
+            open_files: MAX_OPEN_FILES + 1,
+            ..Quota::default()
+        };
+        assert!(Filesystem::new(Box::new(MemoryStorage::new()), quota).is_err());
+    }
+
+    {
+        let quota = Quota {
+            open_files: 1,
+            file_size: 4,
+            total_size: 6,
+        };
+        let mut storage = MemoryStorage::new();
+        storage.files.insert("a".to_string(), b"abc".to_vec());
+        let mut filesystem = Filesystem::new(Box::new(storage), quota).unwrap();
+
+        assert!(filesystem.open("../a", READ_MODE).is_err());
+        assert!(filesystem.open("/a", READ_MODE).is_err());
+        assert!(filesystem.open(".a", READ_MODE).is_err());
+        assert!(filesystem.open("missing", READ_MODE).is_err());
+
+        let fd = filesystem.open("b", WRITE_MODE).unwrap();
+        assert!(filesystem.open("a", READ_MODE).is_err());
+        assert!(filesystem.write(fd, b"abcd").is_err());
+        assert!(filesystem.write(fd, b"abc").is_ok());
+        assert!(filesystem.write(fd, b"d").is_err());
+        assert!(filesystem.seek(fd, 0).is_ok());
+        assert!(filesystem.read(fd, 3).is_ok());
+        assert!(filesystem.close(fd).is_ok());
+        assert!(filesystem.close(fd).is_err());
+
+        let fd = filesystem.open("a", READ_MODE).unwrap();
+        assert!(filesystem.write(fd, b"x").is_err());
+        assert_eq!(b"bc".to_vec(), {
+            filesystem.seek(fd, 1).unwrap();
+            filesystem.read(fd, 8).unwrap()
+        });
+    }
+
+    {
+        let quota = Quota {
+            open_files: 3,
+            file_size: 4,
+            total_size: 6,
+        };
+        let storage = Rc::new(RefCell::new(MemoryStorage::new()));
+        storage.borrow_mut().files.insert("a".to_string(), b"ab".to_vec());
+        let mut filesystem = Filesystem::new(Box::new(storage.clone()), quota).unwrap();
+
+        // unflushed data of the other handle counts
+        let b = filesystem.open("b", WRITE_MODE).unwrap();
+        let c = filesystem.open("c", WRITE_MODE).unwrap();
+        assert!(filesystem.write(b, b"abc").is_ok());
+        assert!(filesystem.write(c, b"ab").is_err());
+        assert!(filesystem.write(c, b"a").is_ok());
+        assert!(filesystem.close(b).is_ok());
+        assert!(filesystem.close(c).is_ok());
+        assert_eq!(6, storage.borrow().used().unwrap());
+
+        // truncating a file frees its space for the others
+        let a = filesystem.open("a", WRITE_MODE).unwrap();
+        let c = filesystem.open("c", APPEND_MODE).unwrap();
+        assert!(filesystem.write(c, b"bc").is_ok());
+        assert!(filesystem.write(a, b"a").is_err());
+
+        assert!(filesystem.open("a", APPEND_MODE).is_err());
+        assert!(filesystem.open("a", READ_MODE).is_ok());
+        assert!(filesystem.close(a).is_ok());
+        assert!(filesystem.open("a", APPEND_MODE).is_ok());
+    }
+
+    {
+        let root = env::temp_dir().join(format!("lulzvm-fs-{}", process::id()));
+        std_fs::create_dir_all(&root).unwrap();
+
+        {
+            let storage = HostDir::new(root.clone()).unwrap();
+            let mut filesystem = Filesystem::new(Box::new(storage), Quota::default()).unwrap();
+            let fd = filesystem.open("file.txt", APPEND_MODE).unwrap();
+            filesystem.write(fd, b"data").unwrap();
+        }
+
+        assert_eq!(b"data".to_vec(), std_fs::read(root.join("file.txt")).unwrap());
+        std_fs::remove_dir_all(&root).unwrap();
+    }
+}
+
+#[rustfmt::skip]
+#[test]
+fn exit_status() {
+    struct Diagnostics(Rc<RefCell<Data>>);
+
+    impl Write for Diagnostics {
+        fn write(&mut self, buffer: &[u8]) -> Result<usize> {
+            self.0.borrow_mut().write(buffer)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            NOP];
+
+        let mut vm = utils::test_vm(&[], executable, 0);
+        assert_eq!(None, vm.exit_status());
+        assert_eq!(None, vm.exit_code());
+        assert_eq!(ExitStatus::Finished, vm.run());
+        assert_eq!(Some(ExitStatus::Finished), vm.exit_status());
+        assert_eq!(Some(0), vm.exit_code());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            PUSH, 0x2a,
+            EMIT, TERMINATE,
+            NOP];
+
+        let mut vm = utils::test_vm(&[], executable, 0);
+        assert_eq!(ExitStatus::Terminated(0x2a), vm.run());
+        assert_eq!(Some(0x2a), vm.exit_code());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            PUSH, INTERRUPTED,             // not an interrupt
+            EMIT, TERMINATE];
+
+        let mut vm = utils::test_vm(&[], executable, 0);
+        assert_eq!(ExitStatus::Terminated(INTERRUPTED), vm.run());
+        assert_eq!(Some(130), vm.exit_code());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            WAIT];
+
+        let mut journal = Journal::new();
+        journal.push(1, Record::Terminate);
+
+        let mut vm = utils::test_vm(&[], executable, 0);
+        vm.replay(journal);
+        assert_eq!(ExitStatus::Interrupted, vm.run());
+        assert_eq!(Some(130), vm.exit_code());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            SUBSCRIBE, TERMINATE, 0x0a, 0x00,
+            WAIT,
+            JMP, 0x06, 0x00,
+
+                                           // handler:
+            POP,                           // INTERRUPTED
+            UNSUBSCRIBE, TERMINATE,
+            PUSH, 0x03,
+            EMIT, TERMINATE];
+
+        let mut vm = utils::test_vm(&[], executable, 0);
+        vm.start();
+        assert!(vm.step());
+        assert!(vm.step());
+        assert!(vm.waiting);
+
+        vm.termination_scheduled.store(true, Ordering::Relaxed);
+        assert!(vm.step());
+        assert!(!vm.flags().termination_scheduled);
+        assert_eq!(0x000a, vm.get_register(PC));
+        assert_eq!(&[INTERRUPTED], vm.locals_stack());
+
+        while vm.step() {}
+        assert_eq!(Some(ExitStatus::Terminated(0x03)), vm.exit_status());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            PUSH, b'x',
+            EMIT, OUTPUT,
+            PUSH, 0x00,
+            PUSH, 0x01,
+            DIV,
+            NOP];
+
+        let diagnostics = Rc::new(RefCell::new(vec![]));
+        let mut vm = utils::test_vm(&[], executable, 0);
+        vm.set_diagnostics(Diagnostics(diagnostics.clone()));
+
+        assert_eq!(ExitStatus::Fault(FaultKind::UnknownError, 0x000a), vm.run());
+        assert_eq!(b"x", vm.get_output_ref().get_ref().as_slice());
+        assert_eq!(b"Unknown Error at 0x000a\n", diagnostics.borrow().as_slice());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            NOP,
+            POP];
+
+        let (_, vm) = utils::test_run(&[], executable, 0);
+        assert_eq!(Some(ExitStatus::Fault(FaultKind::Segfault, 0x0003)), vm.exit_status());
+        assert_eq!(None, vm.exit_code());
+    }
+
+    for &backend in &[Backend::Interpreter, Backend::Predecoded, Backend::Fused] {
+        let executable = vec![
+            0x00, 0x00,
+
+            NOP,
+            0xff];
+
+        let mut vm = utils::test_vm(&[], executable, 0);
+        vm.set_backend(backend);
+        assert_eq!(ExitStatus::Fault(FaultKind::UnknownError, 0x0003), vm.run());
+    }
+}
+
+#[rustfmt::skip]
+#[test]
+fn heap() {
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            PUSH, 0x00,
+            PUSH, 0x03,
+            ALLOC,                         // pointer
+            PUSH, b'a',                    // data
+            PUSH, 0x01,                    // offset
+            STORE_PTR,
+            POP,
+            POP,
+            PUSH, 0x01,                    // offset
+            LOAD_PTR,
+            EMIT, OUTPUT,
+            POP,
+            POP,
+            FREE];
+
+        let mut vm = utils::test_vm(&[], executable, 0);
+        vm.enable_history(8);
+        assert_eq!(ExitStatus::Finished, vm.run());
+
+        let heap_begin = vm.memory.heap_begin;
+        assert_eq!(0x16, heap_begin);
+        assert_eq!(b'a', vm.memory.get(heap_begin + 1));
+        assert!(vm.locals_stack().is_empty());
+        assert_eq!(b"a", vm.get_output_ref().get_ref().as_slice());
+
+        assert!(vm.step_back());
+        assert!(vm.step_back());
+        assert_eq!(&[0x16, 0x00], vm.locals_stack());
+        assert_eq!(Some(0x03), vm.memory.heap.free(heap_begin));
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            PUSH, 0x00,
+            PUSH, 0x02,
+            ALLOC,
+            PUSH, 0x02,                    // out of bounds
+            LOAD_PTR];
+
+        let (_, vm) = utils::test_run(&[], executable.clone(), 0);
+        assert_eq!(&[0x00, 0x02, 0x0a, 0x00], vm.locals_stack());
+
+        let mut vm = utils::test_vm(&[], executable, 0);
+        vm.set_heap_checked(true);
+        assert_eq!(ExitStatus::Fault(FaultKind::Segfault, 0x0009), vm.run());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            PUSH, 0x00,
+            PUSH, 0x01,
+            ALLOC,
+            FREE,
+            PUSH, 0x00,
+            PUSH, 0x0f,                    // the freed pointer
+            PUSH, 0x00,
+            LOAD_PTR];
+
+        let mut vm = utils::test_vm(&[], executable, 0);
+        vm.set_heap_checked(true);
+        assert_eq!(ExitStatus::Fault(FaultKind::Segfault, 0x000e), vm.run());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            PUSH, 0x00,
+            PUSH, 0x01,
+            ALLOC,
+            FREE,
+            PUSH, 0x00,
+            PUSH, 0x0d,
+            FREE];                         // double free
+
+        let (_, vm) = utils::test_run(&[], executable, 0);
+        assert_eq!(Some(ExitStatus::Fault(FaultKind::Segfault, 0x000c)), vm.exit_status());
+    }
+
+    {
+        let mut heap = Heap::new(0x10, 0x20);
+        assert_eq!(None, heap.alloc(0));
+        assert_eq!(None, heap.alloc(0x11));
+        assert_eq!(Some(0x10), heap.alloc(0x04));
+        assert_eq!(Some(0x14), heap.alloc(0x08));
+        assert_eq!(Some(0x1c), heap.alloc(0x04));
+        assert_eq!(None, heap.alloc(0x01));
+        assert_eq!(Some(0x08), heap.free(0x14));
+        assert_eq!(None, heap.free(0x14));
+        assert_eq!(Some(0x14), heap.alloc(0x02));
+        assert_eq!(Some(0x16), heap.alloc(0x06));
+
+        heap.set_checked(true);
+        assert!(heap.is_accessible(0x13));
+        assert!(!heap.is_accessible(0x20));
+        assert_eq!(Some(0x04), heap.free(0x10));
+        assert!(!heap.is_accessible(0x13));
+    }
+}
+
+#[rustfmt::skip]
+#[test]
+fn memory_protection() {
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            PUSH, 0x7b,
+            STORE, 0x17, 0x10];            // locals stack
+
+        let (output, vm) = utils::test_run(&[], executable, 0);
+
+        assert_eq!(0x1017, vm.memory.locals_stack_begin);
+        assert_eq!(0x00, vm.memory.get(0x1017));
+        assert!(output.is_empty());
+        assert_eq!(Some(ExitStatus::Fault(FaultKind::Segfault, 0x0004)), vm.exit_status());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            PUSH, 0x7b,
+            STORE, 0x07, 0x10];            // guard after the heap
+
+        let (_, vm) = utils::test_run(&[], executable, 0);
+
+        assert_eq!(Region::Heap, vm.memory.region(0x1006));
+        assert_eq!(Region::Guard, vm.memory.region(0x1007));
+        assert_eq!(Some(ExitStatus::Fault(FaultKind::Segfault, 0x0004)), vm.exit_status());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            PUSH, 0x01,
+            JMP, 0x02, 0x00];
+
+        let mut vm = utils::test_vm(&[], executable, 0);
+        vm.start();
+        while vm.step() {}
+
+        assert_eq!(LOCALS_STACK_SIZE as usize, vm.locals_stack().len());
+        assert_eq!(Some(ExitStatus::Fault(FaultKind::Segfault, 0x0002)), vm.exit_status());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            SUBSCRIBE, SEGFAULT, 0x09, 0x00,
+            CALL, 0x06, 0x00,
+
+                                           // handler:
+            EMIT, OUTPUT];
+
+        let mut vm = utils::test_vm(&[], executable, 0);
+        vm.start();
+        while vm.step() {}
+
+        assert_eq!(RETURN_STACK_SIZE as usize, vm.return_stack().len());
+        assert!(vm.get_output_ref().get_ref().is_empty());
+        assert_eq!(Some(ExitStatus::Fault(FaultKind::Segfault, 0x0006)), vm.exit_status());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            JMP, 0x06, 0x00,
+            0x00, 0x00];
+
+        let (_, vm) = utils::test_run(&[], executable, 2);
+
+        assert_eq!(Region::Data, vm.memory.region(0x0006));
+        assert!(!Region::Data.permissions().execute);
+        assert_eq!(Some(ExitStatus::Fault(FaultKind::Segfault, 0x0002)), vm.exit_status());
+    }
+}
+
+#[rustfmt::skip]
+#[test]
+fn banked_memory() {
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            PUSH, 0x01,
+            BANK,
+            PUSH, b'a',
+            STORE, 0x00, 0xe0,
+            PUSH, 0x02,
+            BANK,
+            LOAD, 0x00, 0xe0,
+            PUSH, 0x01,
+            BANK,
+            LOAD, 0x00, 0xe0];
+
+        let mut vm = utils::test_vm(&[], executable, 0);
+        vm.enable_history(16);
+        assert_eq!(ExitStatus::Finished, vm.run());
+
+        assert_eq!(&[b'a', 0x00, b'a'], vm.locals_stack());
+        assert_eq!(0x01, vm.memory.bank());
+        assert_eq!(b'a', vm.memory.get(BANK_WINDOW));
+        assert_eq!(Region::Bank, vm.memory.region(BANK_WINDOW));
+
+        assert!(vm.step_back());                   // end of the code
+        assert!(vm.step_back());
+        assert!(vm.step_back());
+        assert_eq!(0x02, vm.memory.bank());
+        assert_eq!(0x00, vm.memory.get(BANK_WINDOW));
+
+        assert!(vm.rewind_to_write(BANK_WINDOW));
+        assert_eq!(0x01, vm.memory.bank());
+        assert_eq!(0x00, vm.memory.get(BANK_WINDOW));
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            PUSH, BANKS,
+            BANK];
+
+        let (_, vm) = utils::test_run(&[], executable, 0);
+        assert_eq!(Some(ExitStatus::Fault(FaultKind::Segfault, 0x0004)), vm.exit_status());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            JMP, 0x00, 0xe0];              // banks aren't executable
+
+        let (_, vm) = utils::test_run(&[], executable, 0);
+        assert_eq!(Some(ExitStatus::Fault(FaultKind::Segfault, 0x0002)), vm.exit_status());
+    }
+
+    {
+        let written = Rc::new(RefCell::new(vec![]));
+        let latch = Latch { written };
+
+        let mut vm = utils::test_vm(&[], vec![0x00, 0x00], 0);
+        assert!(!vm.attach_device(Mapping::Memory(BANK_WINDOW - 2, 4), Box::new(latch)));
+    }
+}
+
+#[rustfmt::skip]
+#[test]
+fn verifier() {
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            SUBSCRIBE, INPUT, 0x10, 0x00,
+            LOAD, 0x11, 0x00,
+            CALL, 0x10, 0x00,
+            WAIT,
+            JMP, 0x11, 0x00,               // end of the code
+            RET,
+            0x00];
+
+        let vm = utils::test_vm(&[], executable, 1);
+        assert_eq!(Ok(()), vm.verify());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            JMP, 0x03, 0x00,               // inside of JMP
+            CALL, 0x00, 0x10,              // outside of the code
+            SUBSCRIBE, INPUT, 0x12, 0x00,  // end of the code
+            STORE, 0x00, 0x00,             // header
+            LOAD, 0x01, 0x00];             // code
+
+        let vm = utils::test_vm(&[], executable, 0);
+        let diagnostics = vm.verify().unwrap_err();
+        assert_eq!(vec![Diagnostic { address: 0x0002, problem: Problem::TargetInsideInstruction(0x0003) },
+                        Diagnostic { address: 0x0005, problem: Problem::TargetOutsideCode(0x1000) },
+                        Diagnostic { address: 0x0008, problem: Problem::TargetOutsideCode(0x0012) },
+                        Diagnostic { address: 0x000c, problem: Problem::AddressOutsideData(0x0000) },
+                        Diagnostic { address: 0x000f, problem: Problem::AddressOutsideData(0x0001) }],
+                   diagnostics);
+        assert_eq!("0x0002: target 0x0003 is inside of an instruction",
+                   diagnostics[0].to_string());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            PUSH, 0x01,
+            0xff,
+            PUSH];
+
+        let vm = utils::test_vm(&[], executable, 0);
+        assert_eq!(Err(vec![Diagnostic { address: 0x0004, problem: Problem::UnknownOpcode(0xff) }]),
+                   vm.verify());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            JMP, 0x00,
+            0x00];
+
+        let vm = utils::test_vm(&[], executable, 1);
+        assert_eq!(Err(vec![Diagnostic { address: 0x0002, problem: Problem::Truncated }]),
+                   vm.verify());
+    }
+}
+
+#[rustfmt::skip]
+#[test]
+fn stack_depth_analysis() {
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            SUBSCRIBE, INPUT, 0x12, 0x00,
+            PUSH, 0x01,
+            PUSH, 0x02,
+            CALL, 0x10, 0x00,              // call f
+            POP,
+            EMIT, TERMINATE,
+
+            ADD,                           // f:
+            RET,
+
+            INC,                           // handler:
+            RETI];
+
+        let vm = utils::test_vm(&[], executable, 0);
+        let usage = StackUsage { locals: Some(3), returns: Some(4) };
+        assert_eq!(Ok(usage), vm.stack_usage());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            PUSH, 0x01,
+            ADD,
+            CALL, 0x09, 0x00,              // call f
+            RET,
+
+            POP,                           // f:
+            POP,
+            RET];
+
+        let vm = utils::test_vm(&[], executable, 0);
+        assert_eq!(Err(vec![Diagnostic { address: 0x0004, problem: Problem::LocalsStackUnderflow },
+                            Diagnostic { address: 0x0005, problem: Problem::LocalsStackUnderflow },
+                            Diagnostic { address: 0x0008, problem: Problem::ReturnStackUnderflow }]),
+                   vm.verify());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            CALL, 0x0a, 0x00,              // call f
+            PUSH, 0x01,                    // loop:
+            JMP, 0x05, 0x00,
+
+            CALL, 0x0a, 0x00,              // f:
+            RET];
+
+        let vm = utils::test_vm(&[], executable, 0);
+        assert_eq!(Err(vec![Diagnostic { address: 0x0005, problem: Problem::UnboundedGrowth },
+                            Diagnostic { address: 0x000a, problem: Problem::UnboundedRecursion }]),
+                   vm.verify());
+    }
+
+    {
+        let mut executable = vec![
+            0x00, 0x00,
+
+            CALL, 0x07, 0x00,
+            EMIT, TERMINATE];
+
+        // each function calls the next one
+        let functions = RETURN_STACK_SIZE / WORD_SIZE;
+        for i in 0..functions {
+            let next = 0x07 + 4 * (i + 1);
+            executable.extend_from_slice(&[CALL, next as u8, (next >> 8) as u8, RET]);
+        }
+        executable.push(RET);
+
+        let vm = utils::test_vm(&[], executable, 0);
+        let diagnostics = vm.verify().unwrap_err();
+        assert_eq!(vec![Diagnostic { address: 0x0002,
+                                     problem: Problem::ReturnStackOverflow(2050) }],
+                   diagnostics);
+        assert_eq!("0x0002: return stack may need 2050 bytes", diagnostics[0].to_string());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            PUSH, 0x05,                    // loop:
+            PUSH, 0x00,
+            PUSH, 0x20,
+            PUSH, 0x00,
+            SYSCALL, FS_READ,
+            POP,
+            JMP, 0x02, 0x00];
+
+        let vm = utils::test_vm(&[], executable, 0);
+        let usage = StackUsage { locals: Some(4), returns: Some(0) };
+        assert_eq!(Ok(usage), vm.stack_usage());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            PUSH, 0x01,
+            SYSCALL, 0x00,
+            SYSCALL, 0x01,
+            POP];
+
+        let mut vm = utils::test_vm(&[], executable, 0);
+        vm.register_host_fn(0x00, Signature { pops: 1, pushes: 2 }, |_| ());
+        let diagnostics = vm.verify().unwrap_err();
+        assert_eq!(vec![Diagnostic { address: 0x0006, problem: Problem::UnknownSyscall(0x01) }],
+                   diagnostics);
+        assert_eq!("0x0006: syscall 0x01 has an unknown stack effect", diagnostics[0].to_string());
+    }
+}
+
+#[test]
+#[rustfmt::skip]
+fn instruction_table() {
+    for info in INSTRUCTIONS {
+        assert_eq!(Some(info), instruction(info.opcode));
+        assert_eq!(Some(info), by_mnemonic(info.mnemonic));
+    }
+    assert_eq!(None, instruction(0xff));
+    assert_eq!(3, instruction(LOAD_OFFS).unwrap().size());
+    assert_eq!(1, instruction(EMIT).unwrap().operands_size());
+
+    assert_eq!(Ok(include_bytes!("../../examples/hello.bin").to_vec()),
+               asm::assemble(include_str!("../../examples/hello.asm")));
+    assert_eq!(Ok(include_bytes!("../../examples/timer.bin").to_vec()),
+               asm::assemble(include_str!("../../examples/timer.asm")));
+
+    {
+        let executable = include_bytes!("../../examples/hello.bin");
+        let source = asm::disassemble(executable);
+        assert!(source.contains("    jge l_0013\n"));
+        assert!(source.contains("    emit terminate\n"));
+        assert_eq!(Ok(executable.to_vec()), asm::assemble(&source));
+    }
+
+    {
+        let executable = vec![
+            0x07, 0x00,
+
+            CALL, 0x08, 0x00,
+            EMIT, TERMINATE,
+            0xff,                          // not an instruction
+            RET,
+
+            0x01, 0x02];
+
+        let source = asm::disassemble(&executable);
+        assert_eq!(".code
+    call 0x0008
+    emit terminate
+    bytes 0xff 0x42
+.data
+    bytes 0x01 0x02
+", source);
+        assert_eq!(Ok(executable), asm::assemble(&source));
+    }
+
+    {
+        let source = "
+            .code
+                PUSH 0x01              ; mnemonics are case-insensitive
+                push 2
+                store [counter]
+            .data
+                counter bytes 0x00
+                greeting ascii \"a;\\\"\\n\"";
+
+        assert_eq!(Ok(vec![0x07, 0x00,
+                           PUSH, 0x01, PUSH, 0x02,
+                           STORE, 0x09, 0x00,
+                           0x00,
+                           b'a', b';', b'"', b'\n']),
+                   asm::assemble(source));
+    }
+
+    let error = |line, message: &str| {
+        Err(asm::Error {
+            line,
+            message: message.to_string(),
+        })
+    };
+    assert_eq!(error(1, "unknown instruction foo"), asm::assemble("foo"));
+    assert_eq!(error(1, "push takes 1 operand(s)"), asm::assemble("push"));
+    assert_eq!(error(2, "invalid operand 0x100"), asm::assemble("\npush 0x100"));
+    assert_eq!(error(1, "invalid operand missing"), asm::assemble("jmp missing"));
+    assert_eq!(error(2, "label a is defined twice"), asm::assemble("a:\na:"));
+    assert_eq!(error(3, "code after data"), asm::assemble(".data\nbytes 0x01\n.code"));
+    assert_eq!("line 3: code after data",
+               asm::assemble(".data\nbytes 0x01\n.code").unwrap_err().to_string());
+}
+
+#[test]
+#[rustfmt::skip]
+fn predecoded_backend() {
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            PUSH, 0x01,
+            0xff,                          // not an instruction
+            JMP, 0x03];                    // truncated
+
+        let mut vm = utils::test_vm(&[], executable, 0);
+        assert_eq!(Backend::Interpreter, vm.backend());
+        vm.set_backend(Backend::Predecoded);
+        assert_eq!(Backend::Predecoded, vm.backend());
+
+        let program = Program::new(&vm.memory, false);
+        assert_eq!(PUSH, program.get(0x0002).unwrap().instruction.opcode);
+        assert_eq!(&[0x01], program.get(0x0002).unwrap().operands());
+        assert_eq!(ADD, program.get(0x0003).unwrap().instruction.opcode);
+        assert!(program.get(0x0004).is_none());
+        assert!(program.get(0x0005).is_none());
+        assert!(program.get(0x0007).is_none());
+        assert!(program.get(0x0000).is_none());
+    }
+
+    {
+        let executable = include_bytes!("../../examples/hello.bin").to_vec();
+        let vm = utils::test_vm(&[], executable, 13);
+        let program = Program::new(&vm.memory, true);
+        assert_eq!(1, program.get(0x0004).unwrap().fused);  // push jge
+        assert_eq!(0, program.get(0x0006).unwrap().fused);
+        assert_eq!(1, program.get(0x0009).unwrap().fused);  // load_offs emit
+        assert_eq!(2, program.get(0x000e).unwrap().fused);  // pop inc jmp
+        assert_eq!(0, program.get(0x0013).unwrap().fused);
+        assert_eq!(0, Program::new(&vm.memory, false).get(0x0009).unwrap().fused);
+    }
+
+    {
+        let executable = include_bytes!("../../examples/hello.bin").to_vec();
+        let mut vm = utils::test_vm(&[], executable, 13);
+        vm.set_backend(Backend::Fused);
+        vm.start();
+        let mut steps = 0;
+        while vm.step() {
+            steps += 1;
+        }
+        assert_eq!(2 + 13 * 6 + 3, vm.steps());
+        // the first JGE is fused with PUSH, EMIT TERMINATE ends the program
+        assert_eq!(1 + 13 * 3 + 2, steps);
+    }
+
+    let run = |executable: Data, data_size, backend| {
+        let mut vm = utils::test_vm(&[], executable, data_size);
+        vm.set_backend(backend);
+        let status = vm.run();
+        let output = vm.get_output_ref().get_ref().to_vec();
+        (output, status, vm.steps(), vm.locals_stack().to_vec(), vm.return_stack().to_vec())
+    };
+
+    let hello = include_bytes!("../../examples/hello.bin").to_vec();
+    let hello_data_size = hello.len() as Word - CODE_OFFSET - Memory::read_word(&hello, 0);
+
+    let programs = vec![
+        (hello, hello_data_size),
+
+        (vec![
+            0x00, 0x00,
+
+            JMP, 0x06, 0x00,
+            PUSH, EMIT, OUTPUT],           // jumps to EMIT
+         0),
+
+        (vec![
+            0x00, 0x00,
+
+            PUSH, 0x03,
+            CALL, 0x0a, 0x00,
+            JMP, 0x0e, 0x00,
+
+            DEC,                           // f:
+            EMIT, OUTPUT,
+            RET,
+
+            PUSH, 0x00,
+            DIV],                          // fault
+         0),
+
+        (vec![
+            0x00, 0x00,
+
+            SUBSCRIBE, OUTPUT, 0x16, 0x00,
+            PUSH, 0x03,
+            PUSH, 0x00,
+            JGE, 0x15, 0x00,               // loop:
+            LOAD_OFFS, 0x19, 0x00,         // interrupted after EMIT
+            EMIT, OUTPUT,
+            POP,
+            INC,
+            JMP, 0x0a, 0x00,
+            NOP,
+
+            PUSH, 0x01,                    // handler:
+            RETI,
+
+            b'a', b'b', b'c'],
+         3),
+
+        (vec![
+            0x00, 0x00,
+
+            SUBSCRIBE, SEGFAULT, 0x0d, 0x00,
+            PUSH, 0x05,
+            POP,
+            INC,                           // fault
+            JMP, 0x06, 0x00,
+
+            EMIT, TERMINATE],              // handler
+         0)];
+
+    for (executable, data_size) in programs {
+        let interpreted = run(executable.clone(), data_size, Backend::Interpreter);
+        let predecoded = run(executable.clone(), data_size, Backend::Predecoded);
+        let fused = run(executable, data_size, Backend::Fused);
+        assert_eq!(interpreted, predecoded);
+        assert_eq!(interpreted, fused);
+    }
+
+    for &backend in &[Backend::Predecoded, Backend::Fused] {
+        let executable = vec![
+            0x00, 0x00,
+
+            PUSH, 0x01,
+            INC,
+            EMIT, OUTPUT];
+
+        let mut vm = utils::test_vm(&[], executable, 0);
+        vm.set_backend(backend);
+        vm.enable_history(16);
+        vm.start();
+        while vm.step() {}
+        assert!(vm.step_back());
+        assert!(vm.step_back());
+        assert!(vm.step_back());
+        assert_eq!([0x01], vm.locals_stack());
+        assert!(vm.step());
+        assert!(vm.step());
+        assert_eq!([0x02], vm.locals_stack());
+    }
+
+    {
+        let executable = vec![
+            0x00, 0x00,
+
+            PUSH, 0x01,
+            PUSH, 0x02,
+            ADD,                           // fused with the PUSH before it
+            EMIT, OUTPUT];
+
+        let mut vm = utils::test_vm(&[], executable.clone(), 0);
+        vm.set_backend(Backend::Fused);
+        vm.start();
+        assert!(vm.step());
+        assert!(vm.step());
+        assert_eq!(0x0007, vm.get_register(PC));
+
+        let mut vm = utils::test_vm(&[], executable, 0);
+        vm.set_backend(Backend::Fused);
+        vm.set_breakpoint(0x0006);
+        vm.start();
+        assert!(vm.step());
+        assert!(vm.step());
+        assert_eq!(0x0006, vm.get_register(PC));
+        assert_eq!([0x02, 0x01], vm.locals_stack());
+        assert!(vm.step());
+        assert_eq!(0x0007, vm.get_register(PC));
+        assert_eq!([0x03], vm.locals_stack());
+
+        vm.clear_breakpoint(0x0006);
+        assert!(vm.breakpoints.is_empty());
+    }
+}
+
+#[test]
+#[rustfmt::skip]
+fn registers_and_flags() {
+    assert_eq!(0x00, PC as u8);
+    assert_eq!(0x05, EE as u8);
+
+    let executable = vec![
+        0x00, 0x00,
+
+        PUSH, 0x01,
+        WAIT];
+
+    let mut vm = utils::test_vm(&[], executable, 0);
+    vm.start();
+    assert!(vm.step());
+
+    let locals_stack_end = vm.memory.locals_stack_end;
+    let return_stack_end = vm.memory.return_stack_end;
+    let event_queue_end = vm.memory.event_queue_end;
+    assert_eq!(Registers { pc: 0x0004,
+                           ir: PUSH as Word,
+                           sp: locals_stack_end - 1,
+                           rp: return_stack_end,
+                           ep: event_queue_end,
+                           ee: event_queue_end },
+               vm.registers());
+    assert_eq!(Flags { termination_scheduled: false, terminated: false, waiting: false },
+               vm.flags());
+
+    assert!(vm.step());
+    assert!(vm.flags().waiting);
+
+    assert!(!vm.set_pc(0x0001));                  // header
+    assert!(!vm.set_pc(0x0006));                  // past the end of the code
+    assert!(vm.set_pc(0x0005));                   // end of the code
+    assert!(vm.set_pc(0x0002));
+    assert_eq!(0x0002, vm.registers().pc);
+
+    vm.termination_scheduled.store(true, Ordering::Relaxed);
+    assert!(vm.flags().termination_scheduled);
+    assert!(!vm.step());
+    assert!(vm.flags().terminated);
+    assert!(!vm.set_pc(0x0002));
+}
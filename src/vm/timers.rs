@@ -0,0 +1,40 @@
+use config::*;
+use std::num::Wrapping;
+
+/// A timer armed by TIMER_SET or TIMER_ONCE, all times are in
+/// milliseconds since the VM has started.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timer {
+    pub period: Word,
+    pub periodic: bool,
+    pub deadline: i64,
+    pub ticks: u8,
+}
+
+impl Timer {
+    pub fn new(period: Word, periodic: bool, now: i64) -> Timer {
+        Timer {
+            period,
+            periodic,
+            deadline: now + period as i64,
+            ticks: 0,
+        }
+    }
+
+    pub fn is_due(&self, now: i64) -> bool {
+        now >= self.deadline
+    }
+
+    /// The timer's state after it has fired, one-shot timers are disarmed.
+    pub fn fired(&self, now: i64) -> Option<Timer> {
+        if self.periodic {
+            Some(Timer {
+                deadline: now + self.period as i64,
+                ticks: (Wrapping(self.ticks) + Wrapping(1)).0,
+                ..*self
+            })
+        } else {
+            None
+        }
+    }
+}
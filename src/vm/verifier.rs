@@ -0,0 +1,592 @@
+use config::*;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use vm::events::{INPUT, TERMINATE};
+use vm::host::Signature;
+use vm::memory::{Memory, Region};
+use vm::opcodes::*;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Problem {
+    UnknownOpcode(u8),
+    /// The operands run past the end of the code.
+    Truncated,
+    TargetOutsideCode(Word),
+    TargetInsideInstruction(Word),
+    /// LOAD or STORE of an address the program can't access.
+    AddressOutsideData(Word),
+    /// There may be fewer items than the instruction (or the function it
+    /// calls) takes.
+    LocalsStackUnderflow,
+    /// RET outside of a function or a handler.
+    ReturnStackUnderflow,
+    /// The locals stack may grow on every pass of the loop starting here.
+    UnboundedGrowth,
+    UnboundedRecursion,
+    /// The program may need more than the stack holds, in bytes.
+    LocalsStackOverflow(u32),
+    ReturnStackOverflow(u32),
+    /// SYSCALL of a host function without a signature, the stack can't be
+    /// followed past it.
+    UnknownSyscall(u8),
+}
+
+/// A problem found in the instruction at `address`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub address: Word,
+    pub problem: Problem,
+}
+
+/// The most the program may use of each stack, in bytes, `None` if it's
+/// unbounded. A handler may interrupt the program anywhere, so the deepest
+/// handler is counted on top of the program's own usage.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StackUsage {
+    pub locals: Option<u32>,
+    pub returns: Option<u32>,
+}
+
+/// Checks the code before it's executed, returns every problem it finds.
+/// Decoding stops at an unknown opcode or a truncated instruction, since
+/// the following instructions can't be told apart.
+///
+/// Addresses beyond the VM's own memory are accepted, devices may get
+/// mapped there later. `signatures` are those of the host functions.
+pub fn verify(memory: &Memory,
+              signatures: &HashMap<u8, Signature>)
+              -> Result<(), Vec<Diagnostic>> {
+    stack_usage(memory, signatures).map(|_| ())
+}
+
+/// Verifies the code and computes how deep the stacks may get. The stack
+/// depth analysis only runs on structurally valid code.
+pub fn stack_usage(memory: &Memory,
+                   signatures: &HashMap<u8, Signature>)
+                   -> Result<StackUsage, Vec<Diagnostic>> {
+    let (instructions, mut diagnostics) = decode(memory);
+    if !diagnostics.is_empty() {
+        return Err(finish(diagnostics));
+    }
+
+    let subscribed = |event| {
+        instructions.values()
+            .any(|instruction| instruction.opcode == SUBSCRIBE && instruction.operands[0] == event)
+    };
+
+    let mut analyzer = Analyzer {
+        memory,
+        instructions: &instructions,
+        signatures,
+        terminate_handled: subscribed(TERMINATE),
+        summaries: HashMap::new(),
+        diagnostics: vec![],
+    };
+
+    let handlers = instructions.iter()
+        .filter(|&(_, instruction)| instruction.opcode == SUBSCRIBE)
+        .map(|(_, instruction)| Memory::read_word(&instruction.operands, 1))
+        .collect::<HashSet<Word>>();
+
+    let mut roots = vec![memory.code_begin];
+    roots.extend(handlers.iter());
+    for function in analyzer.functions(&roots) {
+        let _ = analyzer.procedure(function, Kind::Function);
+    }
+
+    let main = analyzer.procedure(memory.code_begin, Kind::Main);
+    let mut handler_locals = 0;
+    let mut handler_returns = 0;
+    for handler in handlers {
+        let summary = analyzer.procedure(handler, Kind::Handler);
+        handler_locals = cmp::max(handler_locals, HANDLER_ARGUMENT + summary.locals);
+        handler_returns = cmp::max(handler_returns, summary.returns);
+    }
+
+    let locals = bounded(main.locals + handler_locals);
+    let returns = bounded((main.returns + handler_returns) * WORD_SIZE as i64);
+
+    diagnostics.append(&mut analyzer.diagnostics);
+    match locals {
+        Some(locals) if locals > LOCALS_STACK_SIZE as u32 => {
+            let problem = Problem::LocalsStackOverflow(locals);
+            diagnostics.push(Diagnostic::new(memory.code_begin, problem));
+        }
+        _ => (),
+    }
+    match returns {
+        Some(returns) if returns > RETURN_STACK_SIZE as u32 => {
+            let problem = Problem::ReturnStackOverflow(returns);
+            diagnostics.push(Diagnostic::new(memory.code_begin, problem));
+        }
+        _ => (),
+    }
+
+    if diagnostics.is_empty() {
+        Ok(StackUsage { locals, returns })
+    } else {
+        Err(finish(diagnostics))
+    }
+}
+
+struct Decoded {
+    info: &'static Instruction,
+    opcode: u8,
+    operands: Data,
+    next: Word,
+}
+
+fn decode(memory: &Memory) -> (BTreeMap<Word, Decoded>, Vec<Diagnostic>) {
+    let code = memory.code();
+    let mut diagnostics = vec![];
+    let mut instructions = BTreeMap::new();
+    let mut targets = vec![];
+
+    let mut offset = 0;
+    while offset < code.len() {
+        let address = memory.code_begin + offset as Word;
+        let opcode = code[offset];
+        let instruction = match instruction(opcode) {
+            Some(instruction) => instruction,
+            None => {
+                diagnostics.push(Diagnostic::new(address, Problem::UnknownOpcode(opcode)));
+                break;
+            }
+        };
+
+        let size = instruction.operands_size() as usize;
+        if offset + 1 + size > code.len() {
+            diagnostics.push(Diagnostic::new(address, Problem::Truncated));
+            break;
+        }
+
+        let operands = code[(offset + 1)..(offset + 1 + size)].to_vec();
+        let mut position = 0;
+        for operand in instruction.operands {
+            match *operand {
+                Operand::Code => {
+                    targets.push((address, Memory::read_word(&operands, position), true))
+                }
+                Operand::Handler => {
+                    targets.push((address, Memory::read_word(&operands, position), false))
+                }
+                Operand::Data => {
+                    let data = Memory::read_word(&operands, position);
+                    if !is_data_address(memory, data) {
+                        let problem = Problem::AddressOutsideData(data);
+                        diagnostics.push(Diagnostic::new(address, problem));
+                    }
+                }
+                _ => (),
+            }
+            position += operand.size();
+        }
+
+        offset += 1 + size;
+        let next = memory.code_begin + offset as Word;
+        let _ = instructions.insert(address,
+                                    Decoded {
+                                        info: instruction,
+                                        opcode,
+                                        operands,
+                                        next,
+                                    });
+    }
+
+    for (address, target, may_end) in targets {
+        let problem = if may_end && target == memory.code_end {
+            None
+        } else if !memory.is_in_code(target) {
+            Some(Problem::TargetOutsideCode(target))
+        } else if !instructions.contains_key(&target) {
+            Some(Problem::TargetInsideInstruction(target))
+        } else {
+            None
+        };
+
+        if let Some(problem) = problem {
+            diagnostics.push(Diagnostic::new(address, problem));
+        }
+    }
+
+    (instructions, diagnostics)
+}
+
+fn is_data_address(memory: &Memory, address: Word) -> bool {
+    matches!(memory.region(address),
+             Region::Data | Region::Heap | Region::Bank | Region::Device | Region::Unmapped)
+}
+
+fn finish(mut diagnostics: Vec<Diagnostic>) -> Vec<Diagnostic> {
+    diagnostics.sort_by_key(|diagnostic| diagnostic.address);
+    diagnostics.dedup();
+    diagnostics
+}
+
+const UNBOUNDED: i64 = 1 << 32;
+
+// revisits of an instruction before its depth is assumed to change forever
+const WIDENING_DELAY: u32 = 3;
+
+const HANDLER_ARGUMENT: i64 = 1;
+
+fn bounded(value: i64) -> Option<u32> {
+    if value >= UNBOUNDED {
+        None
+    } else {
+        Some(value as u32)
+    }
+}
+
+/// Possible depths of the locals stack relative to the procedure's entry,
+/// negative ones are items the caller has left.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Depth {
+    min: i64,
+    max: i64,
+}
+
+impl Depth {
+    fn join(&self, other: &Depth) -> Depth {
+        Depth {
+            min: cmp::min(self.min, other.min),
+            max: cmp::max(self.max, other.max),
+        }
+    }
+
+    fn shift(&self, min: i64, max: i64) -> Depth {
+        Depth {
+            min: cmp::max(self.min + min, -UNBOUNDED),
+            max: cmp::min(self.max + max, UNBOUNDED),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum Kind {
+    Main,
+    Function,
+    Handler,
+}
+
+impl Kind {
+    /// Items known to be on the locals stack on entry, `None` if it
+    /// depends on the caller.
+    fn base(&self) -> Option<i64> {
+        match *self {
+            Kind::Main => Some(0),
+            Kind::Function => None,
+            Kind::Handler => Some(HANDLER_ARGUMENT),
+        }
+    }
+}
+
+/// What calling a procedure does to the stacks.
+#[derive(Clone, Copy, Debug)]
+struct Summary {
+    /// Items it takes from the caller.
+    need: i64,
+    /// Change of the depth once it returns, `None` if it never does.
+    change: Option<Depth>,
+    /// Most items it puts on top of the caller's ones.
+    locals: i64,
+    /// Most return addresses it needs, its own one included.
+    returns: i64,
+}
+
+struct Analyzer<'a> {
+    memory: &'a Memory,
+    instructions: &'a BTreeMap<Word, Decoded>,
+    signatures: &'a HashMap<u8, Signature>,
+    terminate_handled: bool,
+    summaries: HashMap<(Word, Kind), Summary>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Analyzer<'a> {
+    /// Functions called from `roots`, callees before their callers, so
+    /// every call is summarized before it's needed unless it's recursive.
+    fn functions(&self, roots: &[Word]) -> Vec<Word> {
+        let mut order = vec![];
+        let mut visited = HashSet::new();
+        for &root in roots {
+            if !visited.insert(root) {
+                continue;
+            }
+
+            let mut path = vec![(root, self.callees(root), 0)];
+            while let Some(&mut (entry, ref callees, ref mut index)) = path.last_mut() {
+                if *index < callees.len() {
+                    let callee = callees[*index];
+                    *index += 1;
+                    if visited.insert(callee) {
+                        let callees = self.callees(callee);
+                        path.push((callee, callees, 0));
+                    }
+                } else {
+                    if entry != root {
+                        order.push(entry);
+                    }
+                    let _ = path.pop();
+                }
+            }
+        }
+
+        // a root may be called as well
+        let called = roots.iter()
+            .filter(|root| order.iter().all(|function| function != *root))
+            .filter(|root| self.is_called(**root))
+            .cloned()
+            .collect::<Vec<Word>>();
+        order.extend(called);
+        order
+    }
+
+    fn is_called(&self, entry: Word) -> bool {
+        self.instructions
+            .values()
+            .any(|instruction| {
+                instruction.opcode == CALL && Memory::read_word(&instruction.operands, 0) == entry
+            })
+    }
+
+    /// Targets of the calls reachable from `entry` up to its returns.
+    fn callees(&self, entry: Word) -> Vec<Word> {
+        let mut callees = vec![];
+        let mut visited = HashSet::new();
+        let mut pending = vec![entry];
+        while let Some(address) = pending.pop() {
+            if address == self.memory.code_end || !visited.insert(address) {
+                continue;
+            }
+
+            let instruction = &self.instructions[&address];
+            if instruction.opcode == CALL {
+                let callee = Memory::read_word(&instruction.operands, 0);
+                if callee != self.memory.code_end && !callees.contains(&callee) {
+                    callees.push(callee);
+                }
+            }
+
+            if !self.ends_path(instruction) {
+                pending.extend(successors(instruction));
+            }
+        }
+
+        callees
+    }
+
+    fn ends_path(&self, instruction: &Decoded) -> bool {
+        match instruction.opcode {
+            RET | RETI => true,
+            EMIT => instruction.operands[0] == TERMINATE && !self.terminate_handled,
+            _ => false,
+        }
+    }
+
+    /// Abstract interpretation of the instructions reachable from `entry`
+    /// up to its returns, calls are replaced with the callee's summary.
+    fn procedure(&mut self, entry: Word, kind: Kind) -> Summary {
+        if let Some(summary) = self.summaries.get(&(entry, kind)) {
+            return *summary;
+        }
+
+        let own_returns = if kind == Kind::Main { 0 } else { 1 };
+        let mut summary = Summary {
+            need: 0,
+            change: None,
+            locals: 0,
+            returns: own_returns,
+        };
+
+        let instructions = self.instructions;
+        let mut depths = BTreeMap::new();
+        let mut visits = HashMap::new();
+        let mut pending = vec![entry];
+        let _ = depths.insert(entry, Depth { min: 0, max: 0 });
+
+        while let Some(address) = pending.pop() {
+            let mut depth = depths[&address];
+            let instruction = &instructions[&address];
+            let opcode = instruction.opcode;
+
+            let (need, change) = match opcode {
+                CALL => {
+                    let callee = Memory::read_word(&instruction.operands, 0);
+                    let callee = self.call(address, callee);
+                    summary.locals = cmp::max(summary.locals, depth.max + callee.locals);
+                    summary.returns = cmp::max(summary.returns, own_returns + callee.returns);
+                    (callee.need, callee.change)
+                }
+                RET if kind == Kind::Main => {
+                    self.report(address, Problem::ReturnStackUnderflow);
+                    (0, None)
+                }
+                _ if self.ends_path(instruction) => (0, None),
+                SYSCALL => {
+                    let id = instruction.operands[0];
+                    match self.signatures.get(&id) {
+                        Some(signature) => {
+                            let change = signature.pushes as i64 - signature.pops as i64;
+                            (signature.pops as i64, Some(Depth { min: change, max: change }))
+                        }
+                        None => {
+                            self.report(address, Problem::UnknownSyscall(id));
+                            (0, None)
+                        }
+                    }
+                }
+                EMIT if instruction.operands[0] == INPUT => {
+                    // the default handler pushes the byte once it's read
+                    (0, Some(Depth { min: 0, max: 1 }))
+                }
+                _ => {
+                    let change = instruction.info.stack_change() as i64;
+                    let need = instruction.info.takes as i64;
+                    (need, Some(Depth { min: change, max: change }))
+                }
+            };
+
+            match kind.base() {
+                Some(base) if depth.min + base < need => {
+                    self.report(address, Problem::LocalsStackUnderflow);
+                    depth.min = need - base;
+                    depth.max = cmp::max(depth.max, depth.min);
+                }
+                Some(_) => (),
+                None => summary.need = cmp::max(summary.need, need - depth.min),
+            }
+
+            let change = match change {
+                Some(change) => change,
+                None => {
+                    if opcode == RET || opcode == RETI {
+                        let exit = Some(summary.change.map_or(depth, |exit| exit.join(&depth)));
+                        summary.change = exit;
+                    }
+                    continue;
+                }
+            };
+
+            let next = depth.shift(change.min, change.max);
+            summary.locals = cmp::max(summary.locals, next.max);
+
+            for successor in successors(instruction) {
+                if successor == self.memory.code_end {
+                    continue;
+                }
+
+                let joined = match depths.get(&successor) {
+                    Some(old) => {
+                        let mut joined = old.join(&next);
+                        if joined == *old {
+                            continue;
+                        }
+
+                        let count = visits.entry(successor).or_insert(0);
+                        *count += 1;
+                        if *count > WIDENING_DELAY {
+                            // growth of an unbounded depth is reported where it starts
+                            if joined.max > old.max && next.max < UNBOUNDED {
+                                joined.max = UNBOUNDED;
+                                self.report(successor, Problem::UnboundedGrowth);
+                            }
+                            if joined.min < old.min {
+                                joined.min = -UNBOUNDED;
+                            }
+                        }
+                        joined
+                    }
+                    None => next,
+                };
+
+                let _ = depths.insert(successor, joined);
+                pending.push(successor);
+            }
+        }
+
+        let _ = self.summaries.insert((entry, kind), summary);
+        summary
+    }
+
+    /// Callees are summarized before their callers, so a missing summary
+    /// means the call is recursive.
+    fn call(&mut self, address: Word, callee: Word) -> Summary {
+        if callee == self.memory.code_end {
+            return Summary {
+                need: 0,
+                change: None,
+                locals: 0,
+                returns: 1,
+            };
+        }
+
+        match self.summaries.get(&(callee, Kind::Function)) {
+            Some(summary) => *summary,
+            None => {
+                self.report(address, Problem::UnboundedRecursion);
+                Summary {
+                    need: 0,
+                    change: Some(Depth { min: 0, max: 0 }),
+                    locals: 0,
+                    returns: UNBOUNDED,
+                }
+            }
+        }
+    }
+
+    fn report(&mut self, address: Word, problem: Problem) {
+        self.diagnostics.push(Diagnostic::new(address, problem));
+    }
+}
+
+impl Diagnostic {
+    fn new(address: Word, problem: Problem) -> Diagnostic {
+        Diagnostic { address, problem }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: ", to_hex!(self.address, Word))?;
+        match self.problem {
+            Problem::UnknownOpcode(opcode) => write!(f, "unknown opcode {}", to_hex!(opcode)),
+            Problem::Truncated => write!(f, "truncated instruction"),
+            Problem::TargetOutsideCode(target) => {
+                write!(f, "target {} is outside of the code", to_hex!(target, Word))
+            }
+            Problem::TargetInsideInstruction(target) => {
+                write!(f,
+                       "target {} is inside of an instruction",
+                       to_hex!(target, Word))
+            }
+            Problem::AddressOutsideData(data) => {
+                write!(f, "address {} is outside of the data", to_hex!(data, Word))
+            }
+            Problem::LocalsStackUnderflow => write!(f, "locals stack may underflow"),
+            Problem::ReturnStackUnderflow => write!(f, "return outside of a function"),
+            Problem::UnboundedGrowth => write!(f, "locals stack may grow without bound"),
+            Problem::UnboundedRecursion => write!(f, "recursion may overflow the return stack"),
+            Problem::LocalsStackOverflow(size) => {
+                write!(f, "locals stack may need {} bytes", size)
+            }
+            Problem::ReturnStackOverflow(size) => {
+                write!(f, "return stack may need {} bytes", size)
+            }
+            Problem::UnknownSyscall(id) => {
+                write!(f, "syscall {} has an unknown stack effect", to_hex!(id))
+            }
+        }
+    }
+}
+
+fn successors(instruction: &Decoded) -> Vec<Word> {
+    match instruction.opcode {
+        JMP => vec![Memory::read_word(&instruction.operands, 0)],
+        JE | JNE | JL | JG | JLE | JGE => {
+            vec![Memory::read_word(&instruction.operands, 0), instruction.next]
+        }
+        _ => vec![instruction.next],
+    }
+}
@@ -0,0 +1,28 @@
+extern crate lulzvm;
+
+use lulzvm::bench::{self, CountingAllocator, WORKLOADS};
+use lulzvm::vm::backend::Backend;
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+#[test]
+fn bench_workloads() {
+    let before = bench::allocations();
+    let data = vec![0x00; 16];
+    assert_eq!(before + 1, bench::allocations());
+    drop(data);
+
+    for workload in WORKLOADS {
+        for &backend in &[Backend::Interpreter, Backend::Predecoded, Backend::Fused] {
+            let measurement = bench::measure(workload, backend, 10_000);
+            assert!(measurement.instructions >= 10_000, "{}", workload.name);
+            // nothing is allocated per instruction
+            assert!(measurement.allocations_per_instruction() < 0.01,
+                    "{} {:?} allocates {} times",
+                    workload.name,
+                    backend,
+                    measurement.allocations);
+        }
+    }
+}
@@ -1,16 +1,25 @@
 extern crate lulzvm;
 
 use lulzvm::config::*;
-use lulzvm::utils;
+use lulzvm::vm::backend::Backend;
+use lulzvm::vm::builder::{Config, VmBuilder};
+use lulzvm::vm::memory::{Memory, ProgramTooLarge};
+use lulzvm::vm::status::ExitStatus;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use lulzvm::vm::events::*;
 use lulzvm::vm::opcodes::*;
 
-#[cfg_attr(rustfmt, rustfmt_skip)]
+#[rustfmt::skip]
 #[test]
 fn simple() {
     {
-        let executable = vec![0x00, 0x00];
-        let (output, vm) = utils::test_run(&[], executable, 0);
+        let mut vm = VmBuilder::new()
+            .output(vec![])
+            .build()
+            .unwrap();
+        let _ = vm.run();
+        let output = vm.get_output_ref();
 
         assert!(vm.data().is_empty());
         assert!(vm.locals_stack().is_empty());
@@ -20,11 +29,15 @@ fn simple() {
     }
 
     {
-        let executable = vec![
-            0x00, 0x00,
+        let code = [NOP];
 
-            NOP];
-        let (output, vm) = utils::test_run(&[], executable, 0);
+        let mut vm = VmBuilder::new()
+            .code(&code)
+            .output(vec![])
+            .build()
+            .unwrap();
+        let _ = vm.run();
+        let output = vm.get_output_ref();
 
         assert!(vm.data().is_empty());
         assert!(vm.locals_stack().is_empty());
@@ -34,16 +47,20 @@ fn simple() {
     }
 
     {
-        let executable = vec![
-            0x00, 0x00,
-
+        let code = [
             PUSH, 0x00,                    // b
             PUSH, 0x0a,                    // a
             DEC,                           // a--
             EMIT, OUTPUT,
             JNE, 0x06, 0x00];              // a != b
 
-        let (output, vm) = utils::test_run(&[], executable, 0);
+        let mut vm = VmBuilder::new()
+            .code(&code)
+            .output(vec![])
+            .build()
+            .unwrap();
+        let _ = vm.run();
+        let output = vm.get_output_ref();
 
         assert!(vm.data().is_empty());
         assert_eq!([0x00, 0x00], vm.locals_stack());
@@ -53,9 +70,7 @@ fn simple() {
     }
 
     {
-        let executable = vec![
-            0x00, 0x00,
-
+        let code = [
             PUSH, 0x00,                    // offset
 
             // label loop
@@ -70,10 +85,16 @@ fn simple() {
             JMP, 0x04, 0x00,               // goto loop
 
             // label end
-            NOP,                           // optional
-            0x03, 0x02, 0x01, 0x00];
+            NOP];                          // optional
 
-        let (output, vm) = utils::test_run(&[], executable, 4);
+        let mut vm = VmBuilder::new()
+            .code(&code)
+            .data(&[0x03, 0x02, 0x01, 0x00])
+            .output(vec![])
+            .build()
+            .unwrap();
+        let _ = vm.run();
+        let output = vm.get_output_ref();
 
         assert_eq!(&[0x03, 0x02, 0x01, 0x00], vm.data());
         assert_eq!(&[0x00, 0x00, 0x03], vm.locals_stack());
@@ -83,9 +104,7 @@ fn simple() {
     }
 
     {
-        let executable = vec![
-            0x00, 0x00,
-
+        let code = [
             PUSH, 0x00,                    // b
 
             // label loop
@@ -100,7 +119,14 @@ fn simple() {
             ];
 
         let input = [0x03, 0x02, 0x01, 0x00];
-        let (output, vm) = utils::test_run(&input, executable, 0);
+        let mut vm = VmBuilder::new()
+            .code(&code)
+            .input(&input[..])
+            .output(vec![])
+            .build()
+            .unwrap();
+        let _ = vm.run();
+        let output = vm.get_output_ref();
 
         assert!(vm.data().is_empty());
         assert_eq!(&[0x00, 0x00], vm.locals_stack());
@@ -110,9 +136,7 @@ fn simple() {
     }
 
     {
-        let executable = vec![
-            0x00, 0x00,
-
+        let code = [
             PUSH, 0x00,                    // i
                                            // loop:
             LOAD, 0x13, 0x00,              // x
@@ -121,11 +145,16 @@ fn simple() {
             STORE, 0x13, 0x00,             // x
             POP,                           // pop x
             INC,                           // i++
-            JMP, 0x04, 0x00,               // goto loop
-                                           // end:
-            0x05];                         // x
+            JMP, 0x04, 0x00];              // goto loop
 
-        let (output, vm) = utils::test_run(&[], executable, 1);
+        let mut vm = VmBuilder::new()
+            .code(&code)
+            .data(&[0x05])                 // x
+            .output(vec![])
+            .build()
+            .unwrap();
+        let _ = vm.run();
+        let output = vm.get_output_ref();
 
         assert_eq!(&[0x02], vm.data());
         assert_eq!(&[0x02, 0x03], vm.locals_stack());
@@ -135,9 +164,7 @@ fn simple() {
     }
 
     {
-        let executable = vec![
-            0x00, 0x00,
-
+        let code = [
             PUSH, 0x00,                    // i
             PUSH, 0x05,                    // x
                                            // loop:
@@ -148,7 +175,13 @@ fn simple() {
             JLE, 0x10, 0,                  // if x <= i: goto end
             JMP, 0x06, 0];                 // goto loop
 
-        let (output, vm) = utils::test_run(&[], executable, 0);
+        let mut vm = VmBuilder::new()
+            .code(&code)
+            .output(vec![])
+            .build()
+            .unwrap();
+        let _ = vm.run();
+        let output = vm.get_output_ref();
 
         assert!(vm.data().is_empty());
         assert_eq!(&[0x02, 0x03], vm.locals_stack());
@@ -158,9 +191,7 @@ fn simple() {
     }
 
     {
-        let executable = vec![
-            0x00, 0x00,
-
+        let code = [
             PUSH, 0x00,                    // const zero
                                            // loop:
             EMIT, INPUT,                   //   x = read
@@ -177,7 +208,14 @@ fn simple() {
             RET];
 
         let input = [0x03, 0x02, 0x01, 0x00];
-        let (output, vm) = utils::test_run(&input, executable, 0);
+        let mut vm = VmBuilder::new()
+            .code(&code)
+            .input(&input[..])
+            .output(vec![])
+            .build()
+            .unwrap();
+        let _ = vm.run();
+        let output = vm.get_output_ref();
 
         assert!(vm.data().is_empty());
         assert_eq!(&[0x00, 0x00], vm.locals_stack());
@@ -187,13 +225,11 @@ fn simple() {
     }
 }
 
-#[cfg_attr(rustfmt, rustfmt_skip)]
+#[rustfmt::skip]
 #[test]
 fn io_event() {
     {
-        let executable = vec![
-            0x00, 0x00,
-
+        let code = [
             EMIT, INPUT,
             EMIT, INPUT,
             EMIT, INPUT,
@@ -204,7 +240,14 @@ fn io_event() {
         ];
 
         let input = [0x01, 0x02, 0x03];
-        let (output, vm) = utils::test_run(&input, executable, 0);
+        let mut vm = VmBuilder::new()
+            .code(&code)
+            .input(&input[..])
+            .output(vec![])
+            .build()
+            .unwrap();
+        let _ = vm.run();
+        let output = vm.get_output_ref();
 
         assert!(vm.data().is_empty());
         assert_eq!(&[0x03, 0x02, 0x01], vm.locals_stack());
@@ -214,7 +257,7 @@ fn io_event() {
     }
 }
 
-#[cfg_attr(rustfmt, rustfmt_skip)]
+#[rustfmt::skip]
 #[ignore]
 #[test]
 fn clock_event() {
@@ -223,9 +266,7 @@ fn clock_event() {
         .collect::<Vec<u8>>();
 
     {
-        let executable = vec![
-            0x00, 0x00,
-
+        let code = [
             SUBSCRIBE, CLOCK, 0x0a, 0x00,
 
                                            // loop:
@@ -243,7 +284,13 @@ fn clock_event() {
                                            // exit:
             EMIT, TERMINATE];
 
-        let (output, vm) = utils::test_run(&[], executable, 0);
+        let mut vm = VmBuilder::new()
+            .code(&code)
+            .output(vec![])
+            .build()
+            .unwrap();
+        let _ = vm.run();
+        let output = vm.get_output_ref();
 
         assert!(vm.data().is_empty());
         assert_eq!(&[max_count, max_count], vm.locals_stack());
@@ -253,9 +300,7 @@ fn clock_event() {
     }
 
     {
-        let executable = vec![
-            0x00, 0x00,
-
+        let code = [
             SUBSCRIBE, CLOCK, 0x12, 0x00,  // clock.subscribe(handler)
 
             PUSH, max_count,
@@ -273,7 +318,13 @@ fn clock_event() {
             EMIT, OUTPUT,                  // x = read()
             RET];                          // return x
 
-        let (output, vm) = utils::test_run(&[], executable, 0);
+        let mut vm = VmBuilder::new()
+            .code(&code)
+            .output(vec![])
+            .build()
+            .unwrap();
+        let _ = vm.run();
+        let output = vm.get_output_ref();
 
         assert!(vm.data().is_empty());
         assert_eq!(&[max_count, max_count], vm.locals_stack());
@@ -282,3 +333,74 @@ fn clock_event() {
         assert_eq!(expected_output.as_slice(), output.as_slice());
     }
 }
+
+#[rustfmt::skip]
+#[test]
+fn builder() {
+    {
+        let code = [
+            PUSH, 0x03,
+            PUSH, 0x00,
+            JGE, 0x13, 0x00,               // loop:
+            LOAD_OFFS, 0x13, 0x00,
+            EMIT, OUTPUT,
+            POP,
+            INC,
+            JMP, 0x06, 0x00];
+
+        let builder = VmBuilder::new()
+            .code(&code)
+            .data(b"abc")
+            .input(&b"unused"[..])
+            .output(vec![]);
+        let executable = builder.executable();
+        assert_eq!(&[0x11, 0x00], &executable[..2]);
+        assert_eq!(b"abc", &executable[0x13..]);
+
+        let mut vm = builder.build().unwrap();
+        assert_eq!(ExitStatus::Finished, vm.run());
+        assert_eq!(b"abc", vm.get_output_ref().as_slice());
+        assert_eq!(b"abc", vm.data());
+        assert_eq!(0x13, vm.registers().pc);
+        assert_eq!(0x13, vm.memory().code_end);
+        assert_eq!(HEAP_SIZE, vm.memory().heap_end - vm.memory().heap_begin);
+    }
+
+    {
+        let config = Config {
+            heap_size: 0x10,
+            checked_heap: true,
+            backend: Backend::Fused,
+        };
+        let cancellation_token = Arc::new(AtomicBool::new(false));
+        let mut vm = VmBuilder::new()
+            .code(&[WAIT])
+            .config(config)
+            .cancellation_token(cancellation_token.clone())
+            .build()
+            .unwrap();
+        assert_eq!(Backend::Fused, vm.backend());
+        assert_eq!(0x10, vm.memory().heap_end - vm.memory().heap_begin);
+
+        cancellation_token.store(true, Ordering::Relaxed);
+        assert_eq!(ExitStatus::Interrupted, vm.run());
+    }
+
+    {
+        let max_size = Memory::max_executable_size(HEAP_SIZE);
+        let code = vec![NOP; max_size - CODE_OFFSET as usize];
+        assert!(VmBuilder::new().code(&code).build().is_ok());
+
+        let error = VmBuilder::new().code(&code).data(b"a").build().err().unwrap();
+        assert_eq!(ProgramTooLarge { size: max_size + 1, max_size }, error);
+
+        let config = Config {
+            heap_size: 0x10,
+            ..Config::default()
+        };
+        assert!(VmBuilder::new().code(&code).data(b"a").config(config).build().is_ok());
+
+        let too_large = vec![NOP; Word::MAX as usize + 1];
+        assert!(VmBuilder::new().code(&too_large).build().is_err());
+    }
+}